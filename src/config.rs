@@ -1,6 +1,6 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, path::PathBuf};
 
-use alloy::primitives::{Address, B256};
+use alloy::primitives::{address, Address, B256};
 use reqwest::Url;
 use serde::Deserialize;
 use serde_with::serde_as;
@@ -8,47 +8,312 @@ use serde_with::serde_as;
 #[serde_as]
 #[derive(Deserialize)]
 pub struct Config {
-    /// Authorize signers on startup.
-    pub authorize_signers: bool,
     /// Skip contract calls (for testing/debugging).
     #[serde(default)]
     pub dry_run: bool,
-    /// Table of minimum debts by indexer. This can be used, for example, to account for receipts
-    /// missing from the kafka topic.
-    pub debts: BTreeMap<Address, u64>,
+    /// Chain managed by default, configured at the top level
+    #[serde(flatten)]
+    pub primary: Chain,
+    /// Additional chains, managed in the same process. They share the Kafka consumers, and each
+    /// payer's signers must be distinct across all chains.
+    #[serde(default)]
+    pub chains: Vec<Chain>,
+    /// Kafka configuration
+    pub kafka: Kafka,
+    /// API key for querying subgraphs
+    pub query_auth: String,
+    /// Period of the subgraph polling cycle
+    pub update_interval_seconds: u32,
+    /// Maximum age of the receipts and RAVs data for deposits to be made
+    #[serde(default)]
+    pub max_data_age_seconds: Option<u64>,
+    /// Cap on the total deposits of a cycle while the data is stale. Deposits are skipped if not
+    /// set.
+    #[serde(default)]
+    pub stale_deposit_cap_grt: Option<u64>,
+    /// Port for metrics server
+    #[serde(default = "default_port_metrics")]
+    pub port_metrics: u16,
+}
+
+impl Config {
+    pub fn chains(&self) -> impl Iterator<Item = &Chain> {
+        std::iter::once(&self.primary).chain(&self.chains)
+    }
+}
+
+/// Contracts, network subgraph and payers of a single chain
+#[derive(Deserialize)]
+#[serde(try_from = "RawChain")]
+pub struct Chain {
+    /// Payer managed by default, configured at the top level of the chain
+    pub primary: Payer,
+    /// Additional payers, managed in the same process. They share the Kafka consumers and the
+    /// network subgraph client, and each payer's signers must be distinct.
+    pub payers: Vec<Payer>,
+    /// Network preset the chain was configured from, if any
+    pub network: Option<Network>,
     /// PaymentsEscrow contract address
     pub payments_escrow_contract: Address,
     /// GraphTallyCollector contract address
     pub graph_tally_collector_contract: Address,
+    /// Data service (SubgraphService) contract address. When set, tokens already collected for
+    /// RAVs are subtracted from the debts.
+    pub data_service_contract: Option<Address>,
     /// GRT contract for updating allowance
     pub grt_contract: Address,
-    /// GRT allowance to set on startup
-    pub grt_allowance: u64,
-    /// Kafka configuration
-    pub kafka: Kafka,
     /// Graph network subgraph URL
-    #[serde_as(as = "serde_with::DisplayFromStr")]
     pub network_subgraph: Url,
-    /// API key for querying subgraphs
-    pub query_auth: String,
     /// RPC for executing transactions
-    #[serde_as(as = "serde_with::DisplayFromStr")]
     pub rpc_url: Url,
-    /// Secret key of the TAP payer wallet
-    pub secret_key: B256,
-    /// Secret keys of the TAP signer wallets, used to filter the indexer fees messages.
-    pub signers: Vec<B256>,
-    /// Period of the subgraph polling cycle
-    pub update_interval_seconds: u32,
-    /// Port for metrics server
-    #[serde(default = "default_port_metrics")]
-    pub port_metrics: u16,
+}
+
+/// Chain section as written in the config file, where the contracts and the network subgraph
+/// may be taken from a network preset
+#[serde_as]
+#[derive(Deserialize)]
+struct RawChain {
+    #[serde(flatten)]
+    primary: Payer,
+    #[serde(default)]
+    payers: Vec<Payer>,
+    /// Fill in the contract addresses and the network subgraph of a known network. Fields set
+    /// explicitly take precedence over the preset.
+    #[serde(default)]
+    network: Option<Network>,
+    #[serde(default)]
+    payments_escrow_contract: Option<Address>,
+    #[serde(default)]
+    graph_tally_collector_contract: Option<Address>,
+    #[serde(default)]
+    data_service_contract: Option<Address>,
+    #[serde(default)]
+    grt_contract: Option<Address>,
+    #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    network_subgraph: Option<Url>,
+    #[serde_as(as = "serde_with::DisplayFromStr")]
+    rpc_url: Url,
+}
+
+impl TryFrom<RawChain> for Chain {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawChain) -> anyhow::Result<Self> {
+        let preset = raw.network.map(Network::preset).unwrap_or_default();
+        let required = |field: &str, value: Option<Address>, preset: Option<Address>| {
+            value.or(preset).ok_or_else(|| match raw.network {
+                Some(network) => anyhow::anyhow!(
+                    "missing field `{field}`, which has no preset for network {}",
+                    network.name()
+                ),
+                None => anyhow::anyhow!("missing field `{field}`, or a `network` preset"),
+            })
+        };
+        Ok(Self {
+            payments_escrow_contract: required(
+                "payments_escrow_contract",
+                raw.payments_escrow_contract,
+                preset.payments_escrow,
+            )?,
+            graph_tally_collector_contract: required(
+                "graph_tally_collector_contract",
+                raw.graph_tally_collector_contract,
+                preset.graph_tally_collector,
+            )?,
+            grt_contract: required("grt_contract", raw.grt_contract, preset.grt)?,
+            network_subgraph: match (raw.network_subgraph, preset.network_subgraph) {
+                (Some(url), _) => url,
+                (None, Some(url)) => url.parse()?,
+                (None, None) => anyhow::bail!("missing field `network_subgraph`"),
+            },
+            data_service_contract: raw.data_service_contract,
+            rpc_url: raw.rpc_url,
+            network: raw.network,
+            primary: raw.primary,
+            payers: raw.payers,
+        })
+    }
+}
+
+/// Network with built-in contract addresses and network subgraph
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Network {
+    ArbitrumOne,
+    ArbitrumSepolia,
+}
+
+/// Known contract addresses and network subgraph of a network. Those that are missing must be
+/// configured explicitly.
+#[derive(Default)]
+struct Preset {
+    payments_escrow: Option<Address>,
+    graph_tally_collector: Option<Address>,
+    grt: Option<Address>,
+    network_subgraph: Option<&'static str>,
+}
+
+impl Network {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ArbitrumOne => "arbitrum-one",
+            Self::ArbitrumSepolia => "arbitrum-sepolia",
+        }
+    }
+
+    pub fn chain_id(self) -> u64 {
+        match self {
+            Self::ArbitrumOne => 42161,
+            Self::ArbitrumSepolia => 421614,
+        }
+    }
+
+    fn preset(self) -> Preset {
+        match self {
+            Self::ArbitrumOne => Preset {
+                payments_escrow: Some(address!("0xf6Fcc27aAf1fcD8B254498c9794451d82afC673E")),
+                graph_tally_collector: Some(address!(
+                    "0x8F69F5c07477Ac46fBC491b1E6D91E2bE0111A9E"
+                )),
+                grt: Some(address!("0x9623063377AD1B27544C965cCd7342f7EA7e88C7")),
+                network_subgraph: Some(
+                    "https://gateway.thegraph.com/api/subgraphs/id/DZz4kDTdmzWLWsV373w2bSmoar3umKKH9y82SUKr5qmp",
+                ),
+            },
+            Self::ArbitrumSepolia => Preset {
+                payments_escrow: Some(address!("0x09B985a2042848A08bA59060EaF0f07c6F5D4d54")),
+                graph_tally_collector: Some(address!(
+                    "0x382863e7B662027117449bd2c49285582bbBd21B"
+                )),
+                grt: Some(address!("0xf8c05dCF59E8B28BFD5eed176C562bEbcfc7Ac04")),
+                network_subgraph: Some(
+                    "https://gateway.thegraph.com/api/subgraphs/id/3xQHhMudr1oh69ut36G2mbzpYmYxwqCeU6wwqyCDCnqV",
+                ),
+            },
+        }
+    }
+}
+
+impl Chain {
+    pub fn payers(&self) -> impl Iterator<Item = &Payer> {
+        std::iter::once(&self.primary).chain(&self.payers)
+    }
 }
 
 fn default_port_metrics() -> u16 {
     9090
 }
 
+/// Escrow management of a single TAP payer: its keys, signers and deposit policy
+#[derive(Deserialize)]
+pub struct Payer {
+    /// Authorize signers on startup.
+    pub authorize_signers: bool,
+    /// Table of minimum debts by indexer. This can be used, for example, to account for receipts
+    /// missing from the kafka topic.
+    pub debts: BTreeMap<Address, u64>,
+    /// Additional collectors for which the payer's escrow accounts are managed, independently of
+    /// the GraphTallyCollector. Receipts and RAVs are only tracked for the GraphTallyCollector, so
+    /// the debts for these collectors are their configured minimums.
+    #[serde(default)]
+    pub collectors: Vec<Collector>,
+    /// GRT allowance to set on startup
+    pub grt_allowance: u64,
+    /// Secret key of the TAP payer wallet. When omitted, the service runs in watch-only mode:
+    /// debts, balances and metrics are computed, but all contract calls are refused.
+    #[serde(default)]
+    pub secret_key: Option<B256>,
+    /// Address of the TAP payer. Required when `secret_key` is omitted, and must match it
+    /// otherwise.
+    #[serde(default)]
+    pub payer: Option<Address>,
+    /// Fund the escrow of `payer` from another account, using `PaymentsEscrow.depositTo`. The
+    /// sender is the `secret_key` wallet, or the Safe in safe mode, and holds the GRT and
+    /// allowance. Signers can't be authorized in this mode.
+    #[serde(default)]
+    pub sponsor: bool,
+    /// Address of the sender in sponsor mode, when transactions are exported for offline signing
+    /// and `secret_key` is omitted.
+    #[serde(default)]
+    pub sender: Option<Address>,
+    /// Propose transactions to a Safe multisig acting as the TAP payer, instead of sending them
+    /// from the payer wallet. In this mode, `secret_key` is an owner or delegate of the Safe,
+    /// used to sign the proposals.
+    #[serde(default)]
+    pub safe: Option<Safe>,
+    /// Write unsigned transactions from the payer to this file for offline signing, instead of
+    /// sending them. Once signed, they can be sent using the `broadcast` command.
+    #[serde(default)]
+    pub export_transactions: Option<PathBuf>,
+    /// Path of the journal recording in-flight transactions from the payer wallet. If the process
+    /// stops before a transaction is confirmed, it is awaited on the next start instead of being
+    /// sent again.
+    #[serde(default)]
+    pub journal: Option<PathBuf>,
+    /// Secret keys of the TAP signer wallets, used to filter the indexer fees messages. Only
+    /// required when `authorize_signers` is set.
+    #[serde(default)]
+    pub signers: Vec<B256>,
+    /// Addresses of additional TAP signers, used to filter the indexer fees messages.
+    #[serde(default)]
+    pub signer_addresses: Vec<Address>,
+    /// Also filter the indexer fees messages by the payer's authorized signers, as indexed by the
+    /// network subgraph on startup.
+    #[serde(default)]
+    pub signers_from_subgraph: bool,
+    /// Follow the payer's escrow events from the chain logs, to track deposits, thaws and
+    /// collections per receiver.
+    #[serde(default)]
+    pub log_follower: Option<LogFollower>,
+}
+
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct Safe {
+    /// Safe contract address
+    pub address: Address,
+    /// Safe transaction service to post proposals to, e.g.
+    /// `https://safe-transaction-arbitrum.safe.global/`
+    #[serde_as(as = "Option<serde_with::DisplayFromStr>")]
+    #[serde(default)]
+    pub transaction_service: Option<Url>,
+    /// Directory to write proposals to, as Safe Transaction Builder batches
+    #[serde(default)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Collector {
+    /// Collector contract address
+    pub address: Address,
+    /// Table of minimum debts by receiver, for this collector
+    pub debts: BTreeMap<Address, u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LogFollower {
+    /// File recording the last processed block, to resume from after a restart
+    pub cursor: PathBuf,
+    /// Block to start from when there is no cursor file. Defaults to the latest block.
+    #[serde(default)]
+    pub start_block: Option<u64>,
+    /// Maximum number of blocks per `eth_getLogs` request
+    #[serde(default = "default_max_block_range")]
+    pub max_block_range: u64,
+    /// Number of blocks behind the latest block to follow, so that reorged logs are not applied
+    #[serde(default = "default_confirmations")]
+    pub confirmations: u64,
+}
+
+fn default_max_block_range() -> u64 {
+    10_000
+}
+
+fn default_confirmations() -> u64 {
+    20
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Kafka {
     pub config: BTreeMap<String, String>,
@@ -57,4 +322,158 @@ pub struct Kafka {
     /// Cutoff timestamp (unix milliseconds) for aggregated topic data.
     /// Aggregated records older than this are ignored.
     pub aggregated_cutoff_timestamp: Option<i64>,
+    /// Time limit for replaying the aggregated topic on startup, and for the `plan` command to
+    /// wait for the consumers to catch up
+    #[serde(default = "default_bootstrap_timeout_seconds")]
+    pub bootstrap_timeout_seconds: u64,
+    /// Window of the receipts counted towards the debts
+    #[serde(default = "default_receipts_window_seconds")]
+    pub receipts_window_seconds: u64,
+    /// Size of the time buckets the receipts are aggregated into. Smaller buckets expire receipts
+    /// from the window more precisely, at the cost of memory.
+    #[serde(default = "default_receipts_bucket_seconds")]
+    pub receipts_bucket_seconds: u64,
+    /// Additional short-term window, such as the last hour, over which the receipts are also
+    /// summed
+    #[serde(default)]
+    pub receipts_short_window_seconds: Option<u64>,
+    /// Persist the receipts DB to a local file, to resume from on startup instead of replaying
+    /// the topics.
+    #[serde(default)]
+    pub receipts_snapshot: Option<ReceiptsSnapshot>,
+    /// Commit the consumed realtime topic offsets to the consumer group once they are persisted in
+    /// the receipts snapshot. They are resumed from for the partitions missing from the snapshot.
+    /// Requires `receipts_snapshot`.
+    #[serde(default)]
+    pub commit_offsets: bool,
+    /// Keep a breakdown of the receipts by signer, indexer, deployment and allocation, in
+    /// addition to the totals by signer and indexer.
+    #[serde(default)]
+    pub receipts_breakdown: bool,
+    /// Destination for messages that can't be decoded or are rejected
+    #[serde(default)]
+    pub dead_letter: Option<DeadLetter>,
+    /// Topic to publish escrow events to: planned adjustments, executed and failed deposits,
+    /// thaws and withdrawals
+    #[serde(default)]
+    pub events_topic: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadLetter {
+    /// Kafka topic to produce the messages to, using the consumer `config`
+    Topic(String),
+    /// Local file to append the messages to, as JSON lines
+    File {
+        path: PathBuf,
+        /// Size above which the file is rotated
+        #[serde(default = "default_dead_letter_max_bytes")]
+        max_bytes: u64,
+        /// Number of rotated files to keep
+        #[serde(default = "default_dead_letter_max_files")]
+        max_files: usize,
+    },
+}
+
+fn default_receipts_window_seconds() -> u64 {
+    28 * 24 * 60 * 60
+}
+
+fn default_receipts_bucket_seconds() -> u64 {
+    60 * 60
+}
+
+fn default_bootstrap_timeout_seconds() -> u64 {
+    30 * 60
+}
+
+fn default_dead_letter_max_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_dead_letter_max_files() -> usize {
+    5
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReceiptsSnapshot {
+    /// File to persist the hourly fees and the consumed realtime topic offsets to
+    pub path: PathBuf,
+    /// Period between snapshots
+    #[serde(default = "default_snapshot_interval_seconds")]
+    pub interval_seconds: u64,
+    /// Snapshots older than this are ignored, and the topics are replayed instead
+    #[serde(default = "default_snapshot_max_age_seconds")]
+    pub max_age_seconds: u64,
+}
+
+fn default_snapshot_interval_seconds() -> u64 {
+    300
+}
+
+fn default_snapshot_max_age_seconds() -> u64 {
+    24 * 60 * 60
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::address;
+    use serde_json::json;
+
+    use super::{Chain, Network};
+
+    fn parse(fields: serde_json::Value) -> anyhow::Result<Chain> {
+        let mut chain = json!({
+            "authorize_signers": false,
+            "debts": {},
+            "grt_allowance": 0,
+            "rpc_url": "http://localhost:8545",
+        });
+        chain
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        Ok(serde_json::from_value(chain)?)
+    }
+
+    #[test]
+    fn presets() {
+        for network in [Network::ArbitrumOne, Network::ArbitrumSepolia] {
+            let preset = network.preset();
+            let chain = parse(json!({ "network": network.name() })).unwrap();
+            assert_eq!(Some(chain.payments_escrow_contract), preset.payments_escrow);
+            assert_eq!(
+                Some(chain.graph_tally_collector_contract),
+                preset.graph_tally_collector
+            );
+            assert_eq!(Some(chain.grt_contract), preset.grt);
+            assert_eq!(
+                Some(chain.network_subgraph.as_str()),
+                preset.network_subgraph
+            );
+        }
+    }
+
+    #[test]
+    fn preset_overrides() {
+        let escrow = address!("0x1111111111111111111111111111111111111111");
+        let collector = address!("0x2222222222222222222222222222222222222222");
+        let grt = address!("0x3333333333333333333333333333333333333333");
+        let chain = parse(json!({
+            "network": "arbitrum-one",
+            "payments_escrow_contract": escrow,
+            "graph_tally_collector_contract": collector,
+            "grt_contract": grt,
+            "network_subgraph": "http://localhost:8000/",
+        }))
+        .unwrap();
+        assert_eq!(chain.payments_escrow_contract, escrow);
+        assert_eq!(chain.graph_tally_collector_contract, collector);
+        assert_eq!(chain.grt_contract, grt);
+        assert_eq!(chain.network_subgraph.as_str(), "http://localhost:8000/");
+
+        let err = parse(json!({})).err().unwrap();
+        assert!(err.to_string().contains("payments_escrow_contract"));
+    }
 }
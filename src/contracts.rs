@@ -1,9 +1,14 @@
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use alloy::{
-    network::EthereumWallet,
-    primitives::{keccak256, Address, BlockNumber, Bytes, U256},
+    network::{EthereumWallet, TransactionBuilder as _},
+    primitives::{keccak256, Address, BlockNumber, Bytes, TxHash, B256, U256},
     providers::{DynProvider, Provider as _, ProviderBuilder, WalletProvider},
+    rpc::types::{TransactionReceipt, TransactionRequest},
     signers::{local::PrivateKeySigner, SignerSync as _},
     sol,
     sol_types::SolInterface,
@@ -11,6 +16,8 @@ use alloy::{
 use anyhow::{anyhow, Context as _};
 use reqwest::Url;
 
+use crate::{export, journal, safe};
+
 sol!(
     #[allow(missing_docs)]
     #[sol(rpc)]
@@ -35,46 +42,411 @@ sol!(
 );
 use GraphTallyCollector::{GraphTallyCollectorErrors, GraphTallyCollectorInstance};
 
+/// Maximum number of `tokensCollected` calls per Multicall3 batch
+const TOKENS_COLLECTED_BATCH: usize = 500;
+
+/// How state-changing contract calls are executed
+pub enum Executor {
+    /// Refuse all state-changing calls.
+    WatchOnly,
+    /// Send transactions from the payer wallet.
+    Wallet(PrivateKeySigner),
+    /// Propose transactions to the payer Safe, optionally signed by an owner or delegate.
+    Safe {
+        signer: Option<PrivateKeySigner>,
+        transaction_service: Option<Url>,
+        output: Option<PathBuf>,
+    },
+    /// Write unsigned transactions from the payer to a file, for offline signing.
+    Export(PathBuf),
+}
+
+enum Mode {
+    WatchOnly,
+    Wallet,
+    Safe(Box<safe::Proposer>),
+    Export(export::Exporter),
+}
+
+/// Error returned when a call is rejected because the PaymentsEscrow contract is paused
+#[derive(Debug)]
+pub struct Paused;
+
+impl std::fmt::Display for Paused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PaymentsEscrow is paused")
+    }
+}
+
+impl std::error::Error for Paused {}
+
+/// Outcome of an executed deposit transaction, verified against its `Deposit` events
+#[derive(Debug)]
+pub struct DepositReceipt {
+    pub block_number: BlockNumber,
+    pub tx_hash: TxHash,
+    pub gas_used: u64,
+    pub effective_gas_price: u128,
+    /// Escrow accounts for which the deposited tokens differ from the planned amount
+    pub mismatches: Vec<DepositMismatch>,
+}
+
+#[derive(Debug)]
+pub struct DepositMismatch {
+    pub collector: Address,
+    pub receiver: Address,
+    pub planned: u128,
+    pub deposited: u128,
+}
+
 pub struct Contracts {
     payments_escrow: PaymentsEscrowInstance<DynProvider>,
     graph_tally_collector: GraphTallyCollectorInstance<DynProvider>,
     token: ERC20Instance<DynProvider>,
+    /// TAP payer owning the escrow accounts
     payer: Address,
+    /// Account sending the transactions and funding the deposits. This differs from the payer in
+    /// sponsor mode, where deposits are made using `depositTo`.
+    sender: Address,
+    mode: Mode,
+    journal: Option<journal::Journal>,
 }
 
 impl Contracts {
     pub fn new(
-        payer: PrivateKeySigner,
+        payer: Address,
+        sender: Address,
+        executor: Executor,
         chain_rpc: Url,
         token: Address,
         payments_escrow: Address,
         graph_tally_collector: Address,
-    ) -> Self {
-        let provider = ProviderBuilder::new()
-            .with_simple_nonce_management()
-            .wallet(EthereumWallet::from(payer))
-            .connect_http(chain_rpc);
-        let payer = provider.default_signer_address();
-        let provider = provider.erased();
+    ) -> anyhow::Result<Self> {
+        let (provider, mode) = match executor {
+            Executor::Wallet(wallet) => {
+                let provider = ProviderBuilder::new()
+                    .with_simple_nonce_management()
+                    .wallet(EthereumWallet::from(wallet))
+                    .connect_http(chain_rpc);
+                anyhow::ensure!(
+                    provider.default_signer_address() == sender,
+                    "wallet address does not match sender {sender}"
+                );
+                (provider.erased(), Mode::Wallet)
+            }
+            Executor::WatchOnly => (
+                ProviderBuilder::new().connect_http(chain_rpc).erased(),
+                Mode::WatchOnly,
+            ),
+            Executor::Safe {
+                signer,
+                transaction_service,
+                output,
+            } => {
+                let provider = ProviderBuilder::new().connect_http(chain_rpc).erased();
+                let proposer = safe::Proposer::new(
+                    sender,
+                    provider.clone(),
+                    signer,
+                    transaction_service,
+                    output,
+                )?;
+                (provider, Mode::Safe(Box::new(proposer)))
+            }
+            Executor::Export(output) => {
+                let provider = ProviderBuilder::new().connect_http(chain_rpc).erased();
+                let exporter = export::Exporter::new(provider.clone(), sender, output);
+                (provider, Mode::Export(exporter))
+            }
+        };
         let payments_escrow = PaymentsEscrowInstance::new(payments_escrow, provider.clone());
         let graph_tally_collector =
             GraphTallyCollectorInstance::new(graph_tally_collector, provider.clone());
         let token = ERC20Instance::new(token, provider.clone());
-        Self {
+        Ok(Self {
             payments_escrow,
             graph_tally_collector,
             token,
             payer,
+            sender,
+            mode,
+            journal: None,
+        })
+    }
+
+    /// Record transactions sent from the payer wallet in a journal, so that they are confirmed
+    /// after a restart instead of being sent again.
+    pub fn with_journal(mut self, journal: journal::Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Confirm the in-flight transaction recorded in the journal, if any. Returns the latest block
+    /// if the transaction may have been executed, and fails while it is still pending.
+    pub async fn resolve_journal(&self) -> anyhow::Result<Option<BlockNumber>> {
+        let journal = match &self.journal {
+            Some(journal) => journal,
+            None => return Ok(None),
+        };
+        let entry = match journal.load()? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let provider = self.payments_escrow.provider();
+        if let Some(tx_hash) = entry.tx_hash {
+            let receipt = provider
+                .get_transaction_receipt(tx_hash)
+                .await
+                .context("get transaction receipt")?;
+            if let Some(receipt) = receipt {
+                let block = receipt_block(&receipt)?;
+                tracing::info!(
+                    label = entry.label,
+                    %tx_hash,
+                    block,
+                    status = receipt.status(),
+                    "journal transaction confirmed"
+                );
+                journal.clear()?;
+                return Ok(Some(block));
+            }
         }
+        let nonce = provider
+            .get_transaction_count(self.sender)
+            .await
+            .context("get nonce")?;
+        if nonce > entry.nonce {
+            // The transaction, or one replacing it, has been executed.
+            let block = self.block_number().await?;
+            tracing::info!(
+                label = entry.label,
+                nonce = entry.nonce,
+                "journal transaction nonce used"
+            );
+            journal.clear()?;
+            return Ok(Some(block));
+        }
+        let pending_nonce = provider
+            .get_transaction_count(self.sender)
+            .pending()
+            .await
+            .context("get pending nonce")?;
+        anyhow::ensure!(
+            pending_nonce <= entry.nonce,
+            "journal transaction {} with nonce {} is pending",
+            entry.label,
+            entry.nonce,
+        );
+        tracing::warn!(
+            label = entry.label,
+            nonce = entry.nonce,
+            "journal transaction was not sent, discarding"
+        );
+        journal.clear()?;
+        Ok(None)
     }
 
     pub fn payer(&self) -> Address {
         self.payer
     }
 
+    pub fn sender(&self) -> Address {
+        self.sender
+    }
+
+    pub fn watch_only(&self) -> bool {
+        matches!(self.mode, Mode::WatchOnly)
+    }
+
+    /// Returns true while previously submitted transactions are waiting for signatures.
+    pub async fn pending(&self) -> anyhow::Result<bool> {
+        match &self.mode {
+            Mode::Safe(proposer) => proposer.pending().await,
+            Mode::Export(exporter) => exporter.pending().await,
+            Mode::WatchOnly | Mode::Wallet => Ok(false),
+        }
+    }
+
+    pub fn payments_escrow(&self) -> Address {
+        *self.payments_escrow.address()
+    }
+
+    pub fn collector(&self) -> Address {
+        *self.graph_tally_collector.address()
+    }
+
+    pub async fn chain_id(&self) -> anyhow::Result<u64> {
+        self.payments_escrow
+            .provider()
+            .get_chain_id()
+            .await
+            .context("get chain ID")
+    }
+
+    pub async fn has_code(&self, address: Address) -> anyhow::Result<bool> {
+        let code = self
+            .payments_escrow
+            .provider()
+            .get_code_at(address)
+            .await
+            .with_context(|| format!("get code of {address}"))?;
+        Ok(!code.is_empty())
+    }
+
+    pub async fn block_number(&self) -> anyhow::Result<BlockNumber> {
+        self.payments_escrow
+            .provider()
+            .get_block_number()
+            .await
+            .context("get block number")
+    }
+
+    /// Escrow balance of the receiver, including tokens that are thawing.
+    pub async fn escrow_balance(
+        &self,
+        collector: Address,
+        receiver: Address,
+        block: BlockNumber,
+    ) -> anyhow::Result<u128> {
+        self.payments_escrow
+            .escrowAccounts(self.payer, collector, receiver)
+            .block(block.into())
+            .call()
+            .await
+            .context("get escrow balance")?
+            .balance
+            .try_into()
+            .context("result out of bounds")
+    }
+
+    /// Tokens collected from escrow by the data service for each allocation, keyed by allocation
+    /// ID. The calls are batched through Multicall3, `TOKENS_COLLECTED_BATCH` at a time.
+    pub async fn tokens_collected(
+        &self,
+        data_service: Address,
+        allocations: impl IntoIterator<Item = (Address, Address)>,
+    ) -> anyhow::Result<BTreeMap<Address, u128>> {
+        let allocations: Vec<(Address, Address)> = allocations.into_iter().collect();
+        let provider = self.graph_tally_collector.provider();
+        let mut tokens_collected = BTreeMap::new();
+        for batch in allocations.chunks(TOKENS_COLLECTED_BATCH) {
+            let calls = batch.iter().map(|(allocation, receiver)| {
+                // The collection ID is the allocation ID, left-padded to 32 bytes.
+                let collection_id = B256::left_padding_from(allocation.as_slice());
+                self.graph_tally_collector.tokensCollected(
+                    data_service,
+                    collection_id,
+                    *receiver,
+                    self.payer,
+                )
+            });
+            let results = provider
+                .multicall()
+                .dynamic()
+                .extend(calls)
+                .aggregate3()
+                .await
+                .context("get tokens collected")?;
+            for ((allocation, _), result) in batch.iter().zip(results) {
+                let tokens: u128 = result
+                    .map_err(|failure| {
+                        anyhow!("tokensCollected reverted: {}", failure.return_data)
+                    })?
+                    .try_into()
+                    .context("result out of bounds")?;
+                tokens_collected.insert(*allocation, tokens);
+            }
+        }
+        Ok(tokens_collected)
+    }
+
+    /// Send a transaction from the payer wallet, propose it to the payer Safe, or export it for
+    /// offline signing. Returns `None` if the transaction requires further signatures.
+    async fn send(
+        &self,
+        label: &str,
+        to: Address,
+        data: Bytes,
+        timeout: Duration,
+        map_err: fn(alloy::contract::Error) -> anyhow::Error,
+        deposits: &BTreeMap<(Address, Address), u128>,
+    ) -> anyhow::Result<Option<TransactionReceipt>> {
+        match &self.mode {
+            Mode::WatchOnly => Err(anyhow!("watch-only mode, refusing {label}")),
+            Mode::Safe(proposer) => {
+                proposer.propose(label, to, data).await?;
+                Ok(None)
+            }
+            Mode::Export(exporter) => {
+                exporter.export(label, to, data).await?;
+                Ok(None)
+            }
+            Mode::Wallet => {
+                let provider = self.payments_escrow.provider();
+                let mut tx = TransactionRequest::default().with_to(to).with_input(data);
+                let mut journal_entry = None;
+                if let Some(journal) = &self.journal {
+                    anyhow::ensure!(
+                        journal.load()?.is_none(),
+                        "unresolved journal transaction, refusing {label}"
+                    );
+                    let nonce = provider
+                        .get_transaction_count(self.sender)
+                        .pending()
+                        .await
+                        .context("get nonce")?;
+                    tx.set_nonce(nonce);
+                    let deposits = deposits
+                        .iter()
+                        .map(|((collector, receiver), amount)| journal::Deposit {
+                            collector: *collector,
+                            receiver: *receiver,
+                            amount: *amount,
+                        })
+                        .collect();
+                    let new_entry = journal::Entry {
+                        label: label.to_string(),
+                        nonce,
+                        tx_hash: None,
+                        deposits,
+                    };
+                    journal.write(&new_entry)?;
+                    journal_entry = Some((journal, new_entry));
+                }
+                let pending = match provider.send_transaction(tx).await {
+                    Ok(pending) => pending,
+                    Err(err) => {
+                        // A revert is reported when estimating gas, before the transaction is sent.
+                        if let (Some((journal, _)), alloy::transports::RpcError::ErrorResp(resp)) =
+                            (&journal_entry, &err)
+                        {
+                            if resp.as_revert_data().is_some() {
+                                journal.clear()?;
+                            }
+                        }
+                        return Err(map_err(err.into()));
+                    }
+                };
+                if let Some((journal, mut entry)) = journal_entry {
+                    entry.tx_hash = Some(*pending.tx_hash());
+                    journal.write(&entry)?;
+                }
+                let receipt = pending
+                    .with_timeout(Some(timeout))
+                    .with_required_confirmations(1)
+                    .get_receipt()
+                    .await?;
+                if let Some(journal) = &self.journal {
+                    journal.clear()?;
+                }
+                Ok(Some(receipt))
+            }
+        }
+    }
+
     pub async fn allowance(&self) -> anyhow::Result<u128> {
         self.token
-            .allowance(self.payer(), *self.payments_escrow.address())
+            .allowance(self.sender, *self.payments_escrow.address())
             .call()
             .await
             .context("get allowance")?
@@ -82,63 +454,141 @@ impl Contracts {
             .context("result out of bounds")
     }
 
-    pub async fn approve(&self, amount: u128) -> anyhow::Result<()> {
-        self.token
+    /// Returns the block of the executed transaction, or `None` if it requires further signatures.
+    pub async fn approve(&self, amount: u128) -> anyhow::Result<Option<BlockNumber>> {
+        let data = self
+            .token
             .approve(*self.payments_escrow.address(), U256::from(amount))
-            .send()
-            .await?
-            .with_timeout(Some(Duration::from_secs(30)))
-            .with_required_confirmations(1)
-            .watch()
+            .calldata()
+            .clone();
+        let receipt = self
+            .send(
+                "approve",
+                *self.token.address(),
+                data,
+                Duration::from_secs(30),
+                anyhow::Error::from,
+                &BTreeMap::new(),
+            )
             .await?;
-        Ok(())
+        receipt.as_ref().map(receipt_block).transpose()
     }
 
+    /// Deposit into the escrow accounts given by (collector, receiver). Returns the verified receipt
+    /// of the executed transaction, or `None` if it requires further signatures.
     pub async fn deposit_many(
         &self,
-        deposits: impl IntoIterator<Item = (Address, u128)>,
-    ) -> anyhow::Result<BlockNumber> {
+        deposits: impl IntoIterator<Item = (Address, Address, u128)>,
+    ) -> anyhow::Result<Option<DepositReceipt>> {
+        let mut planned: BTreeMap<(Address, Address), u128> = Default::default();
+        for (collector, receiver, amount) in deposits {
+            *planned.entry((collector, receiver)).or_default() += amount;
+        }
         // Create individual deposit calls for multicall
-        let calls: Vec<Bytes> = deposits
-            .into_iter()
-            .map(|(receiver, amount)| {
-                self.payments_escrow
-                    .deposit(
-                        *self.graph_tally_collector.address(),
-                        receiver,
-                        U256::from(amount),
-                    )
-                    .calldata()
-                    .clone()
+        let calls: Vec<Bytes> = planned
+            .iter()
+            .map(|((collector, receiver), amount)| {
+                let amount = U256::from(*amount);
+                if self.sender == self.payer {
+                    self.payments_escrow
+                        .deposit(*collector, *receiver, amount)
+                        .calldata()
+                        .clone()
+                } else {
+                    self.payments_escrow
+                        .depositTo(self.payer, *collector, *receiver, amount)
+                        .calldata()
+                        .clone()
+                }
             })
             .collect();
 
         // Execute all deposits in a single multicall transaction
+        let data = self.payments_escrow.multicall(calls).calldata().clone();
         let receipt = self
-            .payments_escrow
-            .multicall(calls)
-            .send()
-            .await
-            .map_err(decoded_err::<PaymentsEscrowErrors>)?
-            .with_timeout(Some(Duration::from_secs(30)))
-            .with_required_confirmations(1)
-            .get_receipt()
+            .send(
+                "deposit",
+                *self.payments_escrow.address(),
+                data,
+                Duration::from_secs(30),
+                payments_escrow_err,
+                &planned,
+            )
             .await?;
+        let receipt = match receipt {
+            Some(receipt) => receipt,
+            None => return Ok(None),
+        };
+        anyhow::ensure!(
+            receipt.status(),
+            "deposit transaction {} reverted",
+            receipt.transaction_hash
+        );
 
-        let block_number = receipt
-            .block_number
-            .ok_or_else(|| anyhow!("invalid deposit receipt"))?;
-        Ok(block_number)
+        let mut deposited: BTreeMap<(Address, Address), u128> = Default::default();
+        for log in receipt.logs() {
+            if log.address() != *self.payments_escrow.address() {
+                continue;
+            }
+            let event = match log.log_decode::<PaymentsEscrow::Deposit>() {
+                Ok(log) => log.inner.data,
+                Err(_) => continue,
+            };
+            if event.payer != self.payer {
+                continue;
+            }
+            let tokens: u128 = event.tokens.try_into().context("deposit out of bounds")?;
+            *deposited
+                .entry((event.collector, event.receiver))
+                .or_default() += tokens;
+        }
+        let accounts: BTreeSet<(Address, Address)> =
+            planned.keys().chain(deposited.keys()).copied().collect();
+        let mismatches = accounts
+            .into_iter()
+            .filter_map(|account| {
+                let planned = planned.get(&account).copied().unwrap_or(0);
+                let deposited = deposited.get(&account).copied().unwrap_or(0);
+                let (collector, receiver) = account;
+                (planned != deposited).then_some(DepositMismatch {
+                    collector,
+                    receiver,
+                    planned,
+                    deposited,
+                })
+            })
+            .collect();
+
+        Ok(Some(DepositReceipt {
+            block_number: receipt_block(&receipt)?,
+            tx_hash: receipt.transaction_hash,
+            gas_used: receipt.gas_used,
+            effective_gas_price: receipt.effective_gas_price,
+            mismatches,
+        }))
     }
 
-    pub async fn authorize_signer(&self, signer: &PrivateKeySigner) -> anyhow::Result<()> {
+    /// Returns the block of the executed transaction, or `None` if it requires further signatures.
+    pub async fn authorize_signer(
+        &self,
+        signer: &PrivateKeySigner,
+    ) -> anyhow::Result<Option<BlockNumber>> {
+        anyhow::ensure!(
+            self.sender == self.payer,
+            "signers can only be authorized by the payer {}",
+            self.payer
+        );
         let chain_id = self
             .graph_tally_collector
             .provider()
             .get_chain_id()
             .await
             .context("get chain ID")?;
-        let deadline_offset_s = 60;
+        // Proposed and exported transactions may only be executed once they have been signed.
+        let deadline_offset_s = match self.mode {
+            Mode::Safe(_) | Mode::Export(_) => 60 * 60 * 24 * 7,
+            Mode::WatchOnly | Mode::Wallet => 60,
+        };
         let deadline = U256::from(
             SystemTime::now()
                 .duration_since(UNIX_EPOCH)
@@ -163,17 +613,65 @@ impl Contracts {
             .context("sign authorization proof")?;
         let proof: Bytes = signature.as_bytes().into();
 
-        self.graph_tally_collector
+        let data = self
+            .graph_tally_collector
             .authorizeSigner(signer.address(), deadline, proof)
-            .send()
+            .calldata()
+            .clone();
+        let receipt = self
+            .send(
+                "authorize_signer",
+                *self.graph_tally_collector.address(),
+                data,
+                Duration::from_secs(60),
+                decoded_err::<GraphTallyCollectorErrors>,
+                &BTreeMap::new(),
+            )
+            .await?;
+        receipt.as_ref().map(receipt_block).transpose()
+    }
+
+    pub async fn transaction_count(&self, address: Address) -> anyhow::Result<u64> {
+        self.payments_escrow
+            .provider()
+            .get_transaction_count(address)
             .await
-            .map_err(decoded_err::<GraphTallyCollectorErrors>)?
+            .context("get nonce")
+    }
+
+    /// Broadcast a signed transaction, and return the block it was included in.
+    pub async fn broadcast(&self, raw: &[u8]) -> anyhow::Result<BlockNumber> {
+        let receipt = self
+            .payments_escrow
+            .provider()
+            .send_raw_transaction(raw)
+            .await
+            .map_err(|err| payments_escrow_err(err.into()))?
             .with_timeout(Some(Duration::from_secs(60)))
             .with_required_confirmations(1)
-            .watch()
+            .get_receipt()
             .await?;
-        Ok(())
+        receipt_block(&receipt)
+    }
+}
+
+fn receipt_block(receipt: &TransactionReceipt) -> anyhow::Result<BlockNumber> {
+    receipt
+        .block_number
+        .ok_or_else(|| anyhow!("invalid transaction receipt"))
+}
+
+fn payments_escrow_err(err: alloy::contract::Error) -> anyhow::Error {
+    if let alloy::contract::Error::TransportError(alloy::transports::RpcError::ErrorResp(resp)) =
+        &err
+    {
+        if let Some(PaymentsEscrowErrors::PaymentsEscrowIsPaused(_)) =
+            resp.as_decoded_interface_error::<PaymentsEscrowErrors>()
+        {
+            return anyhow!(Paused);
+        }
     }
+    decoded_err::<PaymentsEscrowErrors>(err)
 }
 
 fn decoded_err<E: SolInterface + std::fmt::Debug>(err: alloy::contract::Error) -> anyhow::Error {
@@ -0,0 +1,269 @@
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::Write as _,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use alloy::hex::ToHexExt as _;
+use anyhow::Context as _;
+use rdkafka::{
+    message::{BorrowedMessage, Header, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+    Message as _,
+};
+use tokio::sync::mpsc;
+
+use crate::{config, kafka};
+
+/// Kafka message that couldn't be processed
+struct Letter {
+    topic: String,
+    partition: i32,
+    offset: i64,
+    /// Message timestamp, in unix milliseconds
+    timestamp: Option<i64>,
+    key: Option<Vec<u8>>,
+    payload: Option<Vec<u8>>,
+    error: String,
+}
+
+/// Destination for Kafka messages that couldn't be processed, to investigate and replay them.
+/// Messages are dropped when no destination is configured.
+#[derive(Clone, Default)]
+pub struct DeadLetters {
+    tx: Option<mpsc::Sender<Letter>>,
+}
+
+impl DeadLetters {
+    pub fn spawn(config: &config::Kafka) -> anyhow::Result<Self> {
+        let sink = match &config.dead_letter {
+            Some(config::DeadLetter::Topic(topic)) => {
+                let producer =
+                    kafka::producer(config).context("failed to create dead-letter producer")?;
+                Sink::Topic(producer, topic.clone())
+            }
+            Some(config::DeadLetter::File {
+                path,
+                max_bytes,
+                max_files,
+            }) => Sink::File(RotatingFile::open(path.clone(), *max_bytes, *max_files)?),
+            None => return Ok(Self::default()),
+        };
+        let (tx, mut rx) = mpsc::channel(1024);
+        tokio::spawn(async move {
+            let mut sink = sink;
+            while let Some(letter) = rx.recv().await {
+                if let Err(dead_letter_err) = sink.write(&letter).await {
+                    tracing::error!(
+                        topic = letter.topic,
+                        partition = letter.partition,
+                        offset = letter.offset,
+                        "{:#}",
+                        dead_letter_err.context("dead letter")
+                    );
+                }
+            }
+        });
+        Ok(Self { tx: Some(tx) })
+    }
+
+    pub fn send(&self, msg: &BorrowedMessage, error: &anyhow::Error) {
+        let tx = match &self.tx {
+            Some(tx) => tx,
+            None => return,
+        };
+        let letter = Letter {
+            topic: msg.topic().to_string(),
+            partition: msg.partition(),
+            offset: msg.offset(),
+            timestamp: msg.timestamp().to_millis(),
+            key: msg.key().map(<[u8]>::to_vec),
+            payload: msg.payload().map(<[u8]>::to_vec),
+            error: format!("{error:#}"),
+        };
+        if tx.try_send(letter).is_err() {
+            tracing::warn!(
+                topic = msg.topic(),
+                partition = msg.partition(),
+                offset = msg.offset(),
+                "dead-letter queue full, dropping message"
+            );
+        }
+    }
+}
+
+enum Sink {
+    /// Produce the original key and payload to this topic, with the source and the error in the
+    /// headers.
+    Topic(FutureProducer, String),
+    /// Append JSON lines, with hex-encoded key and payload
+    File(RotatingFile),
+}
+
+impl Sink {
+    async fn write(&mut self, letter: &Letter) -> anyhow::Result<()> {
+        match self {
+            Self::Topic(producer, topic) => {
+                let partition = letter.partition.to_string();
+                let offset = letter.offset.to_string();
+                let headers = OwnedHeaders::new()
+                    .insert(Header {
+                        key: "source_topic",
+                        value: Some(&letter.topic),
+                    })
+                    .insert(Header {
+                        key: "source_partition",
+                        value: Some(&partition),
+                    })
+                    .insert(Header {
+                        key: "source_offset",
+                        value: Some(&offset),
+                    })
+                    .insert(Header {
+                        key: "error",
+                        value: Some(&letter.error),
+                    });
+                let mut record = FutureRecord::<[u8], [u8]>::to(topic).headers(headers);
+                if let Some(key) = &letter.key {
+                    record = record.key(key);
+                }
+                if let Some(payload) = &letter.payload {
+                    record = record.payload(payload);
+                }
+                producer
+                    .send(record, Duration::from_secs(10))
+                    .await
+                    .map_err(|(produce_err, _)| produce_err)
+                    .with_context(|| format!("produce to {topic}"))?;
+                Ok(())
+            }
+            Self::File(file) => {
+                let line = serde_json::json!({
+                    "topic": letter.topic,
+                    "partition": letter.partition,
+                    "offset": letter.offset,
+                    "timestamp": letter.timestamp,
+                    "key": letter.key.as_ref().map(|k| k.encode_hex()),
+                    "payload": letter.payload.as_ref().map(|p| p.encode_hex()),
+                    "error": letter.error,
+                });
+                file.append(&format!("{line}\n"))
+            }
+        }
+    }
+}
+
+/// File that is rotated to `<path>.1`, `<path>.2`, ... once it exceeds `max_bytes`, keeping at
+/// most `max_files` rotated files.
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> anyhow::Result<Self> {
+        let file = Self::open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn open_append(path: &Path) -> anyhow::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))
+    }
+
+    fn append(&mut self, line: &str) -> anyhow::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file
+            .write_all(line.as_bytes())
+            .with_context(|| format!("failed to write {}", self.path.display()))?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated(index);
+                if from.exists() {
+                    std::fs::rename(&from, self.rotated(index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = Self::open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(format!(".{index}"));
+        path.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::RotatingFile;
+
+    fn read(path: &Path) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead-letters.jsonl");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["a\n", "bbbb\n", "cccccc\n", "d\n", "eeeeeeeeeeee\n"] {
+            file.append(line).unwrap();
+        }
+        assert_eq!(read(&path).as_deref(), Some("eeeeeeeeeeee\n"));
+        assert_eq!(read(&file.rotated(1)).as_deref(), Some("cccccc\nd\n"));
+        assert_eq!(read(&file.rotated(2)).as_deref(), Some("a\nbbbb\n"));
+        assert_eq!(read(&file.rotated(3)), None);
+
+        // Reopening an existing file appends to it, and rotates it once full.
+        drop(file);
+        let mut file = RotatingFile::open(path.clone(), 20, 2).unwrap();
+        file.append("f\n").unwrap();
+        assert_eq!(read(&path).as_deref(), Some("eeeeeeeeeeee\nf\n"));
+        file.append("gggggg\n").unwrap();
+        assert_eq!(read(&path).as_deref(), Some("gggggg\n"));
+        assert_eq!(read(&file.rotated(1)).as_deref(), Some("eeeeeeeeeeee\nf\n"));
+        assert_eq!(read(&file.rotated(2)).as_deref(), Some("cccccc\nd\n"));
+    }
+
+    #[test]
+    fn rotation_without_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead-letters.jsonl");
+        let mut file = RotatingFile::open(path.clone(), 4, 0).unwrap();
+        for line in ["aa\n", "bb\n", "cc\n"] {
+            file.append(line).unwrap();
+        }
+        assert_eq!(read(&path).as_deref(), Some("cc\n"));
+        assert_eq!(read(&file.rotated(1)), None);
+    }
+}
@@ -0,0 +1,450 @@
+use std::time::Duration;
+
+use alloy::primitives::Address;
+use anyhow::Context as _;
+use chrono::{DateTime, Utc};
+use prost::Message as _;
+use rdkafka::producer::FutureRecord;
+use tokio::sync::mpsc;
+
+use crate::{
+    config,
+    contracts::DepositReceipt,
+    kafka,
+    plan::{Deposit, Reason},
+};
+
+/// Publisher of escrow events to a Kafka topic, as `EscrowEventProtobuf` messages keyed by payer.
+/// Events are dropped when no topic is configured. The messages below are published as the schema
+/// in `proto/escrow_events.proto`, and must be kept in sync with it. The tests check the field
+/// numbers and types of each event against the schema.
+#[derive(Clone, Default)]
+pub struct Events {
+    tx: Option<mpsc::Sender<EscrowEventProtobuf>>,
+}
+
+impl Events {
+    pub fn spawn(config: &config::Kafka) -> anyhow::Result<Self> {
+        let topic = match &config.events_topic {
+            Some(topic) => topic.clone(),
+            None => return Ok(Self::default()),
+        };
+        let producer = kafka::producer(config).context("failed to create events producer")?;
+        let (tx, mut rx) = mpsc::channel::<EscrowEventProtobuf>(1024);
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                let key = format!("{:?}", Address::from_slice(&event.payer));
+                let payload = event.encode_to_vec();
+                let record = FutureRecord::to(&topic).key(&key).payload(&payload);
+                if let Err((produce_err, _)) = producer.send(record, Duration::from_secs(10)).await
+                {
+                    tracing::error!(topic, %produce_err, "failed to publish escrow event");
+                }
+            }
+        });
+        Ok(Self { tx: Some(tx) })
+    }
+
+    pub fn publish(&self, chain_id: u64, payer: Address, event: escrow_event::Event) {
+        self.publish_at(chain_id, payer, Utc::now(), event);
+    }
+
+    /// Publish an event that happened at `timestamp`, such as the block time of a chain log.
+    pub fn publish_at(
+        &self,
+        chain_id: u64,
+        payer: Address,
+        timestamp: DateTime<Utc>,
+        event: escrow_event::Event,
+    ) {
+        let tx = match &self.tx {
+            Some(tx) => tx,
+            None => return,
+        };
+        let event = EscrowEventProtobuf {
+            timestamp: timestamp.timestamp_millis(),
+            chain_id,
+            payer: payer.to_vec(),
+            event: Some(event),
+        };
+        if tx.try_send(event).is_err() {
+            tracing::warn!("escrow events queue full, dropping event");
+        }
+    }
+
+    pub fn planned(&self, chain_id: u64, payer: Address, deposits: &[Deposit]) {
+        let event = escrow_event::Event::Planned(AdjustmentsPlannedProtobuf {
+            adjustments: adjustments(deposits),
+        });
+        self.publish(chain_id, payer, event);
+    }
+
+    pub fn deposited(
+        &self,
+        chain_id: u64,
+        payer: Address,
+        deposits: &[Deposit],
+        receipt: &DepositReceipt,
+    ) {
+        let event = escrow_event::Event::Deposited(DepositsExecutedProtobuf {
+            adjustments: adjustments(deposits),
+            tx_hash: receipt.tx_hash.to_vec(),
+            block_number: receipt.block_number,
+            gas_used: receipt.gas_used,
+        });
+        self.publish(chain_id, payer, event);
+    }
+
+    pub fn deposit_failed(
+        &self,
+        chain_id: u64,
+        payer: Address,
+        deposits: &[Deposit],
+        error: &anyhow::Error,
+    ) {
+        let event = escrow_event::Event::DepositFailed(DepositsFailedProtobuf {
+            adjustments: adjustments(deposits),
+            error: format!("{error:#}"),
+        });
+        self.publish(chain_id, payer, event);
+    }
+}
+
+fn adjustments(deposits: &[Deposit]) -> Vec<AdjustmentProtobuf> {
+    deposits
+        .iter()
+        .map(|d| AdjustmentProtobuf {
+            collector: d.collector.to_vec(),
+            receiver: d.receiver.to_vec(),
+            amount_wei: d.amount.to_string(),
+            balance_wei: d.balance.to_string(),
+            debt_wei: d.debt.to_string(),
+            reason: ReasonProtobuf::from(d.reason) as i32,
+            reduced: d.reduced,
+        })
+        .collect()
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct EscrowEventProtobuf {
+    /// Unix milliseconds. For the events observed in the chain logs, this is the block time.
+    #[prost(int64, tag = "1")]
+    timestamp: i64,
+    #[prost(uint64, tag = "2")]
+    chain_id: u64,
+    /// 20 bytes (address)
+    #[prost(bytes, tag = "3")]
+    payer: Vec<u8>,
+    #[prost(oneof = "escrow_event::Event", tags = "10, 11, 12, 13, 14")]
+    event: Option<escrow_event::Event>,
+}
+
+pub mod escrow_event {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Event {
+        /// Deposits planned in a cycle, whether or not they are executed
+        #[prost(message, tag = "10")]
+        Planned(super::AdjustmentsPlannedProtobuf),
+        #[prost(message, tag = "11")]
+        Deposited(super::DepositsExecutedProtobuf),
+        #[prost(message, tag = "12")]
+        DepositFailed(super::DepositsFailedProtobuf),
+        /// Thaw observed in the chain logs
+        #[prost(message, tag = "13")]
+        Thaw(super::ThawProtobuf),
+        /// Withdrawal observed in the chain logs
+        #[prost(message, tag = "14")]
+        Withdraw(super::WithdrawProtobuf),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, prost::Enumeration)]
+#[repr(i32)]
+pub enum ReasonProtobuf {
+    Unspecified = 0,
+    MinimumBalance = 1,
+    Receipts = 2,
+    Ravs = 3,
+    ConfiguredDebt = 4,
+}
+
+impl From<Reason> for ReasonProtobuf {
+    fn from(reason: Reason) -> Self {
+        match reason {
+            Reason::MinimumBalance => Self::MinimumBalance,
+            Reason::Receipts => Self::Receipts,
+            Reason::Ravs => Self::Ravs,
+            Reason::ConfiguredDebt => Self::ConfiguredDebt,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AdjustmentProtobuf {
+    /// 20 bytes (address)
+    #[prost(bytes, tag = "1")]
+    collector: Vec<u8>,
+    /// 20 bytes (address)
+    #[prost(bytes, tag = "2")]
+    receiver: Vec<u8>,
+    /// Decimal string
+    #[prost(string, tag = "3")]
+    amount_wei: String,
+    /// Escrow balance at the time of planning, as a decimal string
+    #[prost(string, tag = "4")]
+    balance_wei: String,
+    /// Decimal string
+    #[prost(string, tag = "5")]
+    debt_wei: String,
+    #[prost(enumeration = "ReasonProtobuf", tag = "6")]
+    reason: i32,
+    /// The amount was reduced to stay within the cap on the total deposits of the cycle.
+    #[prost(bool, tag = "7")]
+    reduced: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AdjustmentsPlannedProtobuf {
+    #[prost(message, repeated, tag = "1")]
+    adjustments: Vec<AdjustmentProtobuf>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DepositsExecutedProtobuf {
+    #[prost(message, repeated, tag = "1")]
+    adjustments: Vec<AdjustmentProtobuf>,
+    /// 32 bytes
+    #[prost(bytes, tag = "2")]
+    tx_hash: Vec<u8>,
+    #[prost(uint64, tag = "3")]
+    block_number: u64,
+    #[prost(uint64, tag = "4")]
+    gas_used: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DepositsFailedProtobuf {
+    #[prost(message, repeated, tag = "1")]
+    adjustments: Vec<AdjustmentProtobuf>,
+    #[prost(string, tag = "2")]
+    error: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ThawProtobuf {
+    /// 20 bytes (address)
+    #[prost(bytes, tag = "1")]
+    pub collector: Vec<u8>,
+    /// 20 bytes (address)
+    #[prost(bytes, tag = "2")]
+    pub receiver: Vec<u8>,
+    /// Total tokens thawing, as a decimal string. Zero when the thaw is cancelled.
+    #[prost(string, tag = "3")]
+    pub tokens_wei: String,
+    /// Unix seconds. Zero when the thaw is cancelled.
+    #[prost(uint64, tag = "4")]
+    pub thaw_end_timestamp: u64,
+    /// 32 bytes
+    #[prost(bytes, tag = "5")]
+    pub tx_hash: Vec<u8>,
+    #[prost(uint64, tag = "6")]
+    pub block_number: u64,
+    #[prost(uint64, tag = "7")]
+    pub log_index: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct WithdrawProtobuf {
+    /// 20 bytes (address)
+    #[prost(bytes, tag = "1")]
+    pub collector: Vec<u8>,
+    /// 20 bytes (address)
+    #[prost(bytes, tag = "2")]
+    pub receiver: Vec<u8>,
+    /// Decimal string
+    #[prost(string, tag = "3")]
+    pub tokens_wei: String,
+    /// 32 bytes
+    #[prost(bytes, tag = "4")]
+    pub tx_hash: Vec<u8>,
+    #[prost(uint64, tag = "5")]
+    pub block_number: u64,
+    #[prost(uint64, tag = "6")]
+    pub log_index: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use prost::Message as _;
+
+    use super::*;
+
+    /// Fields of each message of the schema, by field number: (name, type)
+    fn schema() -> BTreeMap<String, BTreeMap<u32, (String, String)>> {
+        let mut messages: BTreeMap<String, BTreeMap<u32, (String, String)>> = BTreeMap::new();
+        let mut message = None;
+        for line in include_str!("../proto/escrow_events.proto").lines() {
+            let line = line.trim();
+            if let Some(name) = line.strip_prefix("message ") {
+                message = Some(name.trim_end_matches(" {").to_string());
+                continue;
+            }
+            if line.starts_with("enum ") {
+                message = None;
+            }
+            let (Some(message), Some((field, number))) = (&message, line.split_once(" = ")) else {
+                continue;
+            };
+            let number = number.trim_end_matches(';').parse().unwrap();
+            let mut words = field.split_whitespace().filter(|w| *w != "repeated");
+            let (ty, name) = (words.next().unwrap(), words.next().unwrap());
+            messages
+                .entry(message.clone())
+                .or_default()
+                .insert(number, (name.to_string(), ty.to_string()));
+        }
+        messages
+    }
+
+    fn varint(buf: &mut &[u8]) -> u64 {
+        prost::encoding::decode_varint(buf).unwrap()
+    }
+
+    /// Check that the encoded `message` only has fields of the schema, with their wire types, and
+    /// return the names of the fields present.
+    fn check(
+        schema: &BTreeMap<String, BTreeMap<u32, (String, String)>>,
+        message: &str,
+        mut buf: &[u8],
+    ) -> Vec<String> {
+        let fields = &schema[message];
+        let mut present = vec![];
+        while !buf.is_empty() {
+            let key = varint(&mut buf);
+            let (number, wire_type) = ((key >> 3) as u32, key & 7);
+            let (name, ty) = fields
+                .get(&number)
+                .unwrap_or_else(|| panic!("{message}: field {number} not in schema"));
+            present.push(name.clone());
+            match ty.as_str() {
+                "string" | "bytes" => {
+                    assert_eq!(wire_type, 2, "{message}.{name}");
+                    let len = varint(&mut buf) as usize;
+                    buf = &buf[len..];
+                }
+                "int64" | "uint64" | "bool" => {
+                    assert_eq!(wire_type, 0, "{message}.{name}");
+                    varint(&mut buf);
+                }
+                ty if schema.contains_key(ty) => {
+                    assert_eq!(wire_type, 2, "{message}.{name}");
+                    let len = varint(&mut buf) as usize;
+                    let nested = check(schema, ty, &buf[..len]);
+                    let expected: Vec<&String> =
+                        schema[ty].values().map(|(name, _)| name).collect();
+                    assert_eq!(nested.iter().collect::<Vec<_>>(), expected, "{ty}");
+                    buf = &buf[len..];
+                }
+                // enum
+                _ => {
+                    assert_eq!(wire_type, 0, "{message}.{name}");
+                    varint(&mut buf);
+                }
+            }
+        }
+        present
+    }
+
+    fn adjustment() -> AdjustmentProtobuf {
+        AdjustmentProtobuf {
+            collector: vec![1; 20],
+            receiver: vec![2; 20],
+            amount_wei: "3".into(),
+            balance_wei: "4".into(),
+            debt_wei: "5".into(),
+            reason: ReasonProtobuf::Ravs as i32,
+            reduced: true,
+        }
+    }
+
+    #[test]
+    fn events_match_schema() {
+        let schema = schema();
+        let events = [
+            (
+                "planned",
+                escrow_event::Event::Planned(AdjustmentsPlannedProtobuf {
+                    adjustments: vec![adjustment()],
+                }),
+            ),
+            (
+                "deposited",
+                escrow_event::Event::Deposited(DepositsExecutedProtobuf {
+                    adjustments: vec![adjustment()],
+                    tx_hash: vec![6; 32],
+                    block_number: 7,
+                    gas_used: 8,
+                }),
+            ),
+            (
+                "deposit_failed",
+                escrow_event::Event::DepositFailed(DepositsFailedProtobuf {
+                    adjustments: vec![adjustment()],
+                    error: "error".into(),
+                }),
+            ),
+            (
+                "thaw",
+                escrow_event::Event::Thaw(ThawProtobuf {
+                    collector: vec![1; 20],
+                    receiver: vec![2; 20],
+                    tokens_wei: "3".into(),
+                    thaw_end_timestamp: 4,
+                    tx_hash: vec![5; 32],
+                    block_number: 6,
+                    log_index: 7,
+                }),
+            ),
+            (
+                "withdraw",
+                escrow_event::Event::Withdraw(WithdrawProtobuf {
+                    collector: vec![1; 20],
+                    receiver: vec![2; 20],
+                    tokens_wei: "3".into(),
+                    tx_hash: vec![4; 32],
+                    block_number: 5,
+                    log_index: 6,
+                }),
+            ),
+        ];
+        for (variant, event) in events {
+            let event = EscrowEventProtobuf {
+                timestamp: 1,
+                chain_id: 2,
+                payer: vec![3; 20],
+                event: Some(event),
+            };
+            let encoded = event.encode_to_vec();
+            let present = check(&schema, "EscrowEventProtobuf", &encoded);
+            assert_eq!(present, ["timestamp", "chain_id", "payer", variant]);
+            assert_eq!(EscrowEventProtobuf::decode(&*encoded).unwrap(), event);
+        }
+    }
+
+    #[test]
+    fn reasons_match_schema() {
+        let schema = include_str!("../proto/escrow_events.proto");
+        for (name, reason) in [
+            ("UNSPECIFIED", ReasonProtobuf::Unspecified),
+            ("MINIMUM_BALANCE", ReasonProtobuf::MinimumBalance),
+            ("RECEIPTS", ReasonProtobuf::Receipts),
+            ("RAVS", ReasonProtobuf::Ravs),
+            ("CONFIGURED_DEBT", ReasonProtobuf::ConfiguredDebt),
+        ] {
+            let line = format!("REASON_PROTOBUF_{name} = {};", reason as i32);
+            assert!(schema.contains(&line), "{line}");
+        }
+    }
+}
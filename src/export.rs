@@ -0,0 +1,295 @@
+use std::{path::PathBuf, sync::Mutex};
+
+use alloy::{
+    consensus::TxEnvelope,
+    eips::eip2718::Decodable2718 as _,
+    network::TransactionBuilder as _,
+    primitives::{Address, Bytes, TxKind, U256},
+    providers::{DynProvider, Provider as _},
+    rpc::types::TransactionRequest,
+};
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+/// An unsigned EIP-1559 transaction, to be signed offline. The signed transaction is added as
+/// `raw` before the file is passed to the `broadcast` command.
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UnsignedTransaction {
+    pub label: String,
+    pub chain_id: u64,
+    pub from: Address,
+    pub to: Address,
+    pub value: U256,
+    pub data: Bytes,
+    pub nonce: u64,
+    pub gas: u64,
+    #[serde_as(as = "DisplayFromStr")]
+    pub max_fee_per_gas: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub max_priority_fee_per_gas: u128,
+    /// Signed transaction, RLP-encoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw: Option<Bytes>,
+}
+
+impl UnsignedTransaction {
+    /// Returns the signed transaction, after checking that it matches the exported one and that it
+    /// was signed by `from`.
+    pub fn signed(&self) -> anyhow::Result<&Bytes> {
+        let raw = self.raw.as_ref().context("missing signed transaction")?;
+        let envelope = TxEnvelope::decode_2718(&mut raw.as_ref())
+            .context("failed to decode signed transaction")?;
+        let signed = envelope
+            .as_eip1559()
+            .context("signed transaction is not an EIP-1559 transaction")?;
+        let tx = signed.tx();
+        anyhow::ensure!(
+            tx.chain_id == self.chain_id,
+            "signed chain ID {} != {}",
+            tx.chain_id,
+            self.chain_id
+        );
+        anyhow::ensure!(
+            tx.nonce == self.nonce,
+            "signed nonce {} != {}",
+            tx.nonce,
+            self.nonce
+        );
+        anyhow::ensure!(
+            tx.to == TxKind::Call(self.to),
+            "signed recipient {:?} != {}",
+            tx.to,
+            self.to
+        );
+        anyhow::ensure!(
+            tx.value == self.value,
+            "signed value {} != {}",
+            tx.value,
+            self.value
+        );
+        anyhow::ensure!(
+            tx.input == self.data,
+            "signed data differs from exported data"
+        );
+        let signer = signed
+            .signature()
+            .recover_address_from_prehash(&signed.signature_hash())
+            .context("failed to recover signer")?;
+        anyhow::ensure!(
+            signer == self.from,
+            "signed by {signer}, expected {}",
+            self.from
+        );
+        Ok(raw)
+    }
+}
+
+pub fn load(path: &std::path::Path) -> anyhow::Result<Vec<UnsignedTransaction>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    std::fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|s| serde_json::from_str(&s).map_err(anyhow::Error::from))
+        .with_context(|| format!("failed to load transactions from {}", path.display()))
+}
+
+/// Writes transactions from the payer to a file instead of broadcasting them.
+pub struct Exporter {
+    provider: DynProvider,
+    from: Address,
+    output: PathBuf,
+    /// Nonce of the last exported transaction. `None` until restored from the transactions
+    /// exported before a restart.
+    last_nonce: Mutex<Option<Option<u64>>>,
+}
+
+impl Exporter {
+    pub fn new(provider: DynProvider, from: Address, output: PathBuf) -> Self {
+        Self {
+            provider,
+            from,
+            output,
+            last_nonce: Mutex::new(None),
+        }
+    }
+
+    async fn nonce(&self) -> anyhow::Result<u64> {
+        self.provider
+            .get_transaction_count(self.from)
+            .await
+            .context("get nonce")
+    }
+
+    /// Nonce of the last exported transaction. On first use, it is restored from the transactions
+    /// in the output file that haven't been broadcast, so that they are not exported again after a
+    /// restart.
+    async fn last_nonce(&self) -> anyhow::Result<Option<u64>> {
+        if let Some(last_nonce) = *self.last_nonce.lock().unwrap() {
+            return Ok(last_nonce);
+        }
+        let nonce = self.nonce().await?;
+        let txs = load(&self.output)?;
+        let exported = pending_nonce(&txs, self.from, nonce);
+        if let Some(exported) = exported {
+            tracing::info!(nonce, exported, output = %self.output.display(), "exported transactions awaiting broadcast");
+        }
+        Ok(*self.last_nonce.lock().unwrap().get_or_insert(exported))
+    }
+
+    /// Returns true while the last exported transaction hasn't been broadcast.
+    pub async fn pending(&self) -> anyhow::Result<bool> {
+        match self.last_nonce().await? {
+            Some(last_nonce) => Ok(self.nonce().await? <= last_nonce),
+            None => Ok(false),
+        }
+    }
+
+    pub async fn export(&self, label: &str, to: Address, data: Bytes) -> anyhow::Result<()> {
+        let chain_id = self.provider.get_chain_id().await?;
+        let nonce = match self.last_nonce().await? {
+            Some(last_nonce) => self.nonce().await?.max(last_nonce + 1),
+            None => self.nonce().await?,
+        };
+        let tx = TransactionRequest::default()
+            .with_from(self.from)
+            .with_to(to)
+            .with_input(data.clone())
+            .with_nonce(nonce);
+        let gas = self
+            .provider
+            .estimate_gas(tx)
+            .await
+            .with_context(|| format!("estimate gas for {label}"))?;
+        let fees = self
+            .provider
+            .estimate_eip1559_fees()
+            .await
+            .context("estimate fees")?;
+        let tx = UnsignedTransaction {
+            label: label.to_string(),
+            chain_id,
+            from: self.from,
+            to,
+            value: U256::ZERO,
+            data,
+            nonce,
+            gas,
+            max_fee_per_gas: fees.max_fee_per_gas,
+            max_priority_fee_per_gas: fees.max_priority_fee_per_gas,
+            raw: None,
+        };
+
+        let mut txs = load(&self.output)?;
+        txs.push(tx);
+        crate::journal::write_json_atomic(&self.output, &txs)
+            .with_context(|| format!("failed to write {}", self.output.display()))?;
+        tracing::info!(label, nonce, output = %self.output.display(), "transaction exported");
+        *self.last_nonce.lock().unwrap() = Some(Some(nonce));
+        Ok(())
+    }
+}
+
+/// Highest nonce of the transactions exported for `from` that haven't been broadcast, given its
+/// current `nonce`.
+fn pending_nonce(txs: &[UnsignedTransaction], from: Address, nonce: u64) -> Option<u64> {
+    txs.iter()
+        .filter(|tx| (tx.from == from) && (tx.nonce >= nonce))
+        .map(|tx| tx.nonce)
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::{
+        consensus::{SignableTransaction as _, TxEip1559, TxEnvelope},
+        eips::eip2718::Encodable2718 as _,
+        primitives::{address, Bytes, TxKind, U256},
+        signers::{local::PrivateKeySigner, SignerSync as _},
+    };
+
+    use super::{pending_nonce, UnsignedTransaction};
+
+    fn sign(signer: &PrivateKeySigner, tx: &UnsignedTransaction) -> Bytes {
+        let unsigned = TxEip1559 {
+            chain_id: tx.chain_id,
+            nonce: tx.nonce,
+            gas_limit: tx.gas,
+            max_fee_per_gas: tx.max_fee_per_gas,
+            max_priority_fee_per_gas: tx.max_priority_fee_per_gas,
+            to: TxKind::Call(tx.to),
+            value: tx.value,
+            input: tx.data.clone(),
+            ..Default::default()
+        };
+        let signature = signer.sign_hash_sync(&unsigned.signature_hash()).unwrap();
+        TxEnvelope::from(unsigned.into_signed(signature))
+            .encoded_2718()
+            .into()
+    }
+
+    #[test]
+    fn signed() {
+        let signer = PrivateKeySigner::random();
+        let mut tx = UnsignedTransaction {
+            label: "deposit".into(),
+            chain_id: 42161,
+            from: signer.address(),
+            to: address!("0x1111111111111111111111111111111111111111"),
+            value: U256::ZERO,
+            data: Bytes::from_static(&[1, 2, 3]),
+            nonce: 7,
+            gas: 100_000,
+            max_fee_per_gas: 1_000_000_000,
+            max_priority_fee_per_gas: 1_000_000,
+            raw: None,
+        };
+        assert!(tx.signed().is_err());
+        tx.raw = Some(sign(&signer, &tx));
+        assert_eq!(tx.signed().unwrap(), tx.raw.as_ref().unwrap());
+
+        tx.nonce = 8;
+        assert!(tx.signed().is_err());
+        tx.nonce = 7;
+        tx.chain_id = 1;
+        assert!(tx.signed().is_err());
+        tx.chain_id = 42161;
+        tx.data = Bytes::from_static(&[1, 2]);
+        assert!(tx.signed().is_err());
+        tx.data = Bytes::from_static(&[1, 2, 3]);
+        tx.to = address!("0x2222222222222222222222222222222222222222");
+        assert!(tx.signed().is_err());
+        tx.to = address!("0x1111111111111111111111111111111111111111");
+        assert!(tx.signed().is_ok());
+
+        tx.from = PrivateKeySigner::random().address();
+        assert!(tx.signed().is_err());
+    }
+
+    #[test]
+    fn pending_after_restart() {
+        let from = address!("0x1111111111111111111111111111111111111111");
+        let other = address!("0x2222222222222222222222222222222222222222");
+        let tx = |from, nonce| UnsignedTransaction {
+            label: "deposit".into(),
+            chain_id: 42161,
+            from,
+            to: other,
+            value: U256::ZERO,
+            data: Bytes::new(),
+            nonce,
+            gas: 100_000,
+            max_fee_per_gas: 1_000_000_000,
+            max_priority_fee_per_gas: 1_000_000,
+            raw: None,
+        };
+        let txs = vec![tx(from, 3), tx(from, 4), tx(other, 9)];
+        assert_eq!(pending_nonce(&txs, from, 3), Some(4));
+        assert_eq!(pending_nonce(&txs, from, 4), Some(4));
+        assert_eq!(pending_nonce(&txs, from, 5), None);
+        assert_eq!(pending_nonce(&[], from, 0), None);
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use alloy::primitives::{Address, TxHash};
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+/// Transaction from the payer that was sent, or was about to be sent, and hasn't been confirmed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub label: String,
+    pub nonce: u64,
+    /// Hash of the sent transaction, unknown if the process stopped while sending it
+    pub tx_hash: Option<TxHash>,
+    pub deposits: Vec<Deposit>,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Deposit {
+    pub collector: Address,
+    pub receiver: Address,
+    #[serde_as(as = "DisplayFromStr")]
+    pub amount: u128,
+}
+
+/// On-disk record of the in-flight transaction, so that it is confirmed after a restart instead of
+/// being sent again. Transactions from the payer are sent one at a time, so there is at most one
+/// entry.
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn load(&self) -> anyhow::Result<Option<Entry>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let entry = std::fs::read_to_string(&self.path)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| serde_json::from_str(&s).map_err(anyhow::Error::from))
+            .with_context(|| format!("failed to load journal {}", self.path.display()))?;
+        Ok(Some(entry))
+    }
+
+    pub fn write(&self, entry: &Entry) -> anyhow::Result<()> {
+        write_json_atomic(&self.path, entry)
+            .with_context(|| format!("failed to write journal {}", self.path.display()))
+    }
+
+    pub fn clear(&self) -> anyhow::Result<()> {
+        if self.path.exists() {
+            std::fs::remove_file(&self.path)
+                .with_context(|| format!("failed to clear journal {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `value` as JSON to a temporary file next to `path`, syncs it and renames it to `path`, so
+/// that the file is never left partially written.
+pub fn write_json_atomic<T: Serialize + ?Sized>(path: &Path, value: &T) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let file = std::fs::File::create(&tmp)
+        .with_context(|| format!("failed to create {}", tmp.display()))?;
+    let mut writer = std::io::BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, value)?;
+    writer.flush()?;
+    writer.into_inner()?.sync_all()?;
+    std::fs::rename(&tmp, path)
+        .with_context(|| format!("failed to rename {} to {}", tmp.display(), path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_json_atomic;
+
+    #[test]
+    fn write_json_atomic_replaces_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        write_json_atomic(&path, &[1, 2]).unwrap();
+        write_json_atomic(&path, &[3]).unwrap();
+        let value: Vec<u32> =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(value, vec![3]);
+        assert!(!path.with_extension("tmp").exists());
+    }
+}
@@ -1,10 +1,31 @@
-pub use ravs::ravs;
-use rdkafka::consumer::StreamConsumer;
-pub use receipts::receipts;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use crate::config;
+use anyhow::{anyhow, Context as _};
+use chrono::Utc;
+use futures_util::FutureExt as _;
+pub use ravs::{ravs, Ravs};
+use rdkafka::{
+    consumer::{Consumer as _, StreamConsumer},
+    error::KafkaError,
+    message::BorrowedMessage,
+    producer::FutureProducer,
+    Message as _, Offset, TopicPartitionList,
+};
+pub use receipts::{receipts, Fees, Receipts};
+use tokio::sync::watch;
 
-fn consumer(config: &config::Kafka) -> anyhow::Result<StreamConsumer> {
+use crate::{config, dead_letter::DeadLetters, metrics};
+
+fn consumer(config: &config::Kafka, auto_commit: bool) -> anyhow::Result<StreamConsumer> {
     let mut consumer_config = rdkafka::ClientConfig::from_iter(config.config.clone());
     let defaults = [
         ("group.id", "tap-escrow-manager"),
@@ -16,95 +37,787 @@ fn consumer(config: &config::Kafka) -> anyhow::Result<StreamConsumer> {
             consumer_config.set(key, value);
         }
     }
+    if !auto_commit {
+        // Offsets are committed explicitly, once they are safe to resume from.
+        consumer_config.set("enable.auto.commit", "false");
+    }
+    // End of partition events mark the consumers as caught up.
+    consumer_config.set("enable.partition.eof", "true");
     Ok(consumer_config.create()?)
 }
 
+/// Properties of the shared client config that only apply to consumers
+const CONSUMER_PROPERTIES: &[&str] = &[
+    "group.id",
+    "group.instance.id",
+    "group.protocol",
+    "group.protocol.type",
+    "group.remote.assignor",
+    "partition.assignment.strategy",
+    "session.timeout.ms",
+    "heartbeat.interval.ms",
+    "coordinator.query.interval.ms",
+    "max.poll.interval.ms",
+    "enable.auto.commit",
+    "auto.commit.interval.ms",
+    "enable.auto.offset.store",
+    "auto.offset.reset",
+    "queued.min.messages",
+    "queued.max.messages.kbytes",
+    "fetch.wait.max.ms",
+    "fetch.queue.backoff.ms",
+    "fetch.message.max.bytes",
+    "max.partition.fetch.bytes",
+    "fetch.max.bytes",
+    "fetch.min.bytes",
+    "fetch.error.backoff.ms",
+    "isolation.level",
+    "enable.partition.eof",
+    "check.crcs",
+];
+
+/// Producer sharing the client config of the consumers, without their consumer-only properties
+pub fn producer(config: &config::Kafka) -> anyhow::Result<FutureProducer> {
+    let producer_config = rdkafka::ClientConfig::from_iter(
+        config
+            .config
+            .iter()
+            .filter(|(key, _)| !CONSUMER_PROPERTIES.contains(&key.as_str()))
+            .map(|(key, value)| (key.clone(), value.clone())),
+    );
+    Ok(producer_config.create()?)
+}
+
+/// Whether a receive error requires the consumer to be restarted. Other errors, such as broker
+/// transport failures, are recovered from by librdkafka.
+fn fatal(consumer: &StreamConsumer, err: &KafkaError) -> bool {
+    matches!(err, KafkaError::MessageConsumptionFatal(_))
+        || consumer.client().fatal_error().is_some()
+}
+
+/// Count a message that can't be processed, and send it to the dead-letter destination.
+fn reject(msg: &BorrowedMessage, reject_err: &anyhow::Error, dead_letters: &DeadLetters) {
+    metrics::METRICS
+        .rejected_messages
+        .with_label_values(&[msg.topic()])
+        .inc();
+    dead_letters.send(msg, reject_err);
+}
+
+/// Assign the partitions of the topic at the given offsets. If `committed` is set, partitions
+/// without an offset are assigned at the offset committed to the consumer group. The given offsets
+/// take precedence, since the committed offsets may lag behind them. Other partitions start from
+/// the first message at or after `start_timestamp`. Returns the offsets the partitions were
+/// assigned at, excluding those resolved from `start_timestamp`.
+fn assign_offsets(
+    consumer: &StreamConsumer,
+    topic: &str,
+    offsets: &BTreeMap<i32, i64>,
+    start_timestamp: i64,
+    committed: bool,
+) -> anyhow::Result<BTreeMap<i32, i64>> {
+    let timeout = std::time::Duration::from_secs(30);
+    let partitions = partitions(consumer, topic)?;
+    let mut offsets = offsets.clone();
+    if committed {
+        for (partition, offset) in committed_offsets(consumer, topic, &partitions)? {
+            offsets.entry(partition).or_insert(offset);
+        }
+    }
+    let mut assignment = TopicPartitionList::new();
+    let mut timestamps = TopicPartitionList::new();
+    for partition in partitions {
+        match offsets.get(&partition) {
+            Some(offset) => {
+                assignment.add_partition_offset(topic, partition, Offset::Offset(*offset))?;
+            }
+            None => {
+                timestamps.add_partition_offset(
+                    topic,
+                    partition,
+                    Offset::Offset(start_timestamp),
+                )?;
+            }
+        };
+    }
+    if timestamps.count() > 0 {
+        for elem in consumer.offsets_for_times(timestamps, timeout)?.elements() {
+            assignment.add_partition_offset(topic, elem.partition(), elem.offset())?;
+        }
+    }
+    consumer.assign(&assignment)?;
+    Ok(offsets)
+}
+
+/// Offsets committed to the consumer group, for the partitions that have one
+fn committed_offsets(
+    consumer: &StreamConsumer,
+    topic: &str,
+    partitions: &[i32],
+) -> anyhow::Result<BTreeMap<i32, i64>> {
+    let mut list = TopicPartitionList::new();
+    for partition in partitions {
+        list.add_partition(topic, *partition);
+    }
+    let committed = consumer
+        .committed_offsets(list, std::time::Duration::from_secs(30))
+        .context("fetch committed offsets")?;
+    Ok(committed
+        .elements()
+        .iter()
+        .filter_map(|elem| match elem.offset() {
+            Offset::Offset(offset) => Some((elem.partition(), offset)),
+            _ => None,
+        })
+        .collect())
+}
+
+fn partitions(consumer: &StreamConsumer, topic: &str) -> anyhow::Result<Vec<i32>> {
+    let metadata = consumer
+        .fetch_metadata(Some(topic), Duration::from_secs(30))
+        .with_context(|| format!("fetch metadata of {topic}"))?;
+    Ok(metadata
+        .topics()
+        .iter()
+        .flat_map(|t| t.partitions())
+        .map(|p| p.id())
+        .collect())
+}
+
+/// How current the data of a consumer is
+#[derive(Clone)]
+pub struct Freshness {
+    topic: String,
+    /// Unix milliseconds up to which the data is known to be current: the timestamp of the latest
+    /// processed message, or the last time the consumer was observed without lag or reached the
+    /// end of a partition.
+    current_at: Arc<AtomicI64>,
+    /// Unset while the consumer task is being restarted
+    healthy: Arc<AtomicBool>,
+    /// Partitions of which the end hasn't been reached yet, once they are known
+    pending: Arc<Mutex<Option<BTreeSet<i32>>>>,
+    /// Set once the consumer has reached the end of each of its partitions
+    ready: watch::Sender<bool>,
+}
+
+impl Freshness {
+    fn new(topic: &str) -> Self {
+        let freshness = Self {
+            topic: topic.to_string(),
+            current_at: Default::default(),
+            healthy: Default::default(),
+            pending: Default::default(),
+            ready: watch::Sender::new(false),
+        };
+        freshness.set_healthy(true);
+        metrics::METRICS
+            .kafka_consumer_ready
+            .with_label_values(&[topic])
+            .set(0);
+        freshness
+    }
+
+    /// Whether the consumer has caught up with the messages produced before it was assigned
+    pub fn is_ready(&self) -> bool {
+        *self.ready.borrow()
+    }
+
+    /// Wait for the consumer to be ready.
+    pub async fn ready(&self) {
+        let _ = self.ready.subscribe().wait_for(|ready| *ready).await;
+    }
+
+    /// Consider the consumer ready once it has reached the end of each of `partitions`.
+    fn expect_partitions(&self, partitions: impl IntoIterator<Item = i32>) {
+        let pending: BTreeSet<i32> = partitions.into_iter().collect();
+        let empty = pending.is_empty();
+        *self.pending.lock().unwrap() = Some(pending);
+        if empty {
+            self.set_ready();
+        }
+    }
+
+    fn partition_eof(&self, partition: i32) {
+        self.current_at
+            .fetch_max(Utc::now().timestamp_millis(), Ordering::Relaxed);
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(pending) = pending.as_mut() {
+            if pending.remove(&partition) && pending.is_empty() {
+                self.set_ready();
+            }
+        }
+    }
+
+    fn set_ready(&self) {
+        if !self.ready.send_replace(true) {
+            tracing::info!(topic = self.topic, "kafka consumer caught up");
+            metrics::METRICS
+                .kafka_consumer_ready
+                .with_label_values(&[&self.topic])
+                .set(1);
+        }
+    }
+
+    /// Age of the data, or `None` if the consumer hasn't caught up with any message yet, or is
+    /// being restarted.
+    pub fn age(&self) -> Option<Duration> {
+        if !self.healthy.load(Ordering::Relaxed) {
+            return None;
+        }
+        let current_at = self.current_at.load(Ordering::Relaxed);
+        if current_at == 0 {
+            return None;
+        }
+        let age = Utc::now().timestamp_millis().saturating_sub(current_at);
+        Some(Duration::from_millis(age.max(0) as u64))
+    }
+
+    fn processed(&self, msg: &BorrowedMessage) {
+        let timestamp = match msg.timestamp().to_millis() {
+            Some(timestamp) => timestamp,
+            None => return,
+        };
+        metrics::METRICS
+            .kafka_message_timestamp
+            .with_label_values(&[msg.topic()])
+            .set(timestamp / 1_000);
+        self.current_at.fetch_max(timestamp, Ordering::Relaxed);
+    }
+
+    fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+        metrics::METRICS
+            .kafka_consumer_healthy
+            .with_label_values(&[&self.topic])
+            .set(healthy as i64);
+    }
+
+    /// Periodically export the lag of each partition assigned to the consumer, and consider the
+    /// data current whenever there is no lag.
+    fn monitor(&self, consumer: Arc<StreamConsumer>) {
+        let freshness = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(30));
+            loop {
+                interval.tick().await;
+                let checked_at = Utc::now().timestamp_millis();
+                let consumer = consumer.clone();
+                match tokio::task::spawn_blocking(move || lag(&consumer)).await {
+                    Ok(Ok(Some(0))) => {
+                        freshness
+                            .current_at
+                            .fetch_max(checked_at, Ordering::Relaxed);
+                    }
+                    Ok(Ok(_)) => (),
+                    Ok(Err(lag_err)) => tracing::warn!("{:#}", lag_err.context("consumer lag")),
+                    Err(join_err) => tracing::warn!(%join_err),
+                };
+            }
+        });
+    }
+}
+
+/// Restarts a consumer task with exponential backoff whenever it fails or panics. The data of the
+/// consumer is unhealthy from the failure until the task marks it healthy again.
+struct Supervisor {
+    freshness: Freshness,
+    backoff: Duration,
+}
+
+impl Supervisor {
+    const MIN_BACKOFF: Duration = Duration::from_secs(1);
+    const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+    fn new(freshness: Freshness) -> Self {
+        Self {
+            freshness,
+            backoff: Self::MIN_BACKOFF,
+        }
+    }
+
+    /// Run the consumer task until it stops, then wait before it may be restarted.
+    async fn run(&mut self, task: impl Future<Output = anyhow::Result<()>>) {
+        let start = Instant::now();
+        let consumer_err = match AssertUnwindSafe(task).catch_unwind().await {
+            Ok(Ok(())) => anyhow!("stopped"),
+            Ok(Err(consumer_err)) => consumer_err,
+            Err(_) => anyhow!("panicked"),
+        };
+        self.freshness.set_healthy(false);
+        metrics::METRICS
+            .kafka_consumer_restarts
+            .with_label_values(&[&self.freshness.topic])
+            .inc();
+        if start.elapsed() > Self::MAX_BACKOFF {
+            self.backoff = Self::MIN_BACKOFF;
+        }
+        tracing::error!(
+            topic = self.freshness.topic,
+            backoff_seconds = self.backoff.as_secs(),
+            "{:#}",
+            consumer_err.context("kafka consumer")
+        );
+        tokio::time::sleep(self.backoff).await;
+        self.backoff = (self.backoff * 2).min(Self::MAX_BACKOFF);
+    }
+}
+
+/// Export the lag of each assigned partition, and return the total lag if it is known for all of
+/// them.
+fn lag(consumer: &StreamConsumer) -> anyhow::Result<Option<i64>> {
+    let position = consumer.position()?;
+    if position.count() == 0 {
+        return Ok(None);
+    }
+    let mut total = Some(0);
+    for elem in position.elements() {
+        let (low, high) =
+            consumer.fetch_watermarks(elem.topic(), elem.partition(), Duration::from_secs(10))?;
+        let lag = match elem.offset() {
+            Offset::Offset(offset) => (high - offset).max(0),
+            // Nothing consumed yet from an empty partition
+            _ if low == high => 0,
+            _ => {
+                total = None;
+                continue;
+            }
+        };
+        metrics::METRICS
+            .kafka_lag
+            .with_label_values(&[elem.topic(), &elem.partition().to_string()])
+            .set(lag);
+        total = total.map(|t| t + lag);
+    }
+    Ok(total)
+}
+
 mod receipts {
-    use std::collections::BTreeMap;
+    use std::{collections::BTreeMap, path::Path, sync::Arc};
 
-    use alloy::{hex::ToHexExt as _, primitives::Address};
+    use alloy::{
+        hex::ToHexExt as _,
+        primitives::{Address, B256},
+    };
     use anyhow::{anyhow, Context as _};
     use chrono::{DateTime, Duration, Utc};
     use futures_util::StreamExt as _;
     use prost::Message as _;
     use rdkafka::{
-        consumer::{Consumer as _, StreamConsumer},
-        Message as _,
+        consumer::{CommitMode, Consumer as _, StreamConsumer},
+        error::KafkaError,
+        message::BorrowedMessage,
+        Message as _, Offset, TopicPartitionList,
     };
-    use titorelli::kafka::{assign_partitions, latest_messages};
+    use serde::{Deserialize, Serialize};
+    use serde_with::{serde_as, DisplayFromStr};
+    use titorelli::kafka::assign_partitions;
     use tokio::sync::{mpsc, watch};
 
-    use super::consumer;
-    use crate::config;
+    use super::{
+        assign_offsets, committed_offsets, consumer, fatal, partitions, Freshness, Supervisor,
+    };
+    use crate::{config, dead_letter::DeadLetters, metrics};
+
+    /// Bucket limit per key, to keep the memory of the DB bounded
+    const MAX_BUCKETS: u64 = 10_000;
+
+    /// Fees from receipts within the window
+    pub struct Receipts {
+        /// Fees keyed by (signer, indexer)
+        pub fees: watch::Receiver<BTreeMap<(Address, Address), Fees>>,
+        /// Fees keyed by signer, indexer, deployment and allocation, if enabled
+        pub breakdown: Option<watch::Receiver<BTreeMap<BreakdownKey, u128>>>,
+        /// Freshness of the realtime topic data
+        pub freshness: Freshness,
+    }
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct Fees {
+        /// Fees within the window
+        pub total: u128,
+        /// Fees within the short-term window, if configured
+        pub recent: Option<u128>,
+    }
+
+    /// Time buckets the fees are aggregated into
+    #[derive(Clone, Copy)]
+    pub struct Buckets {
+        /// Bucket size, in seconds
+        size: i64,
+        window: Duration,
+        short_window: Option<Duration>,
+    }
+
+    impl Buckets {
+        fn new(config: &config::Kafka) -> anyhow::Result<Self> {
+            let size = config.receipts_bucket_seconds;
+            let window = config.receipts_window_seconds;
+            anyhow::ensure!(size > 0, "receipts_bucket_seconds must be positive");
+            anyhow::ensure!(
+                window >= size,
+                "receipts_window_seconds must be at least receipts_bucket_seconds"
+            );
+            anyhow::ensure!(
+                window.div_ceil(size) <= MAX_BUCKETS,
+                "receipts_window_seconds spans more than {MAX_BUCKETS} buckets of \
+                 receipts_bucket_seconds"
+            );
+            if let Some(short_window) = config.receipts_short_window_seconds {
+                anyhow::ensure!(
+                    (size..=window).contains(&short_window),
+                    "receipts_short_window_seconds must be between receipts_bucket_seconds and \
+                     receipts_window_seconds"
+                );
+            }
+            Ok(Self {
+                size: size as i64,
+                window: Duration::seconds(window as i64),
+                short_window: config
+                    .receipts_short_window_seconds
+                    .map(|w| Duration::seconds(w as i64)),
+            })
+        }
+
+        /// Start of the bucket containing `t`, in unix seconds
+        fn start(&self, t: DateTime<Utc>) -> i64 {
+            let t = t.timestamp();
+            t - t.rem_euclid(self.size)
+        }
+
+        /// Split the fee of the hour starting at `hour` (unix seconds) across the buckets it
+        /// overlaps, in proportion to the overlap. Returns the start of each part, in unix seconds,
+        /// and its fee. The rounding remainder is added to the first part.
+        fn spread_hour(&self, hour: i64, fee: u128) -> Vec<(i64, u128)> {
+            let hour_seconds = Duration::hours(1).num_seconds();
+            let end = hour + hour_seconds;
+            let mut parts = vec![];
+            let mut start = hour;
+            while start < end {
+                let next = (start - start.rem_euclid(self.size) + self.size).min(end);
+                let overlap = (next - start) as u128;
+                let hour_seconds = hour_seconds as u128;
+                // Exact `fee * overlap / hour_seconds`, without overflow
+                let part =
+                    (fee / hour_seconds) * overlap + (fee % hour_seconds) * overlap / hour_seconds;
+                parts.push((start, part));
+                start = next;
+            }
+            let total: u128 = parts.iter().map(|(_, fee)| fee).sum();
+            parts[0].1 += fee - total;
+            parts
+        }
+    }
+
+    /// Key of the fees breakdown. The deployment and allocation are only available for the
+    /// realtime topic messages that include them.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+    pub struct BreakdownKey {
+        pub signer: Address,
+        pub indexer: Address,
+        pub deployment: Option<B256>,
+        pub allocation: Option<Address>,
+    }
 
     pub async fn receipts(
         config: &config::Kafka,
         signers: Vec<Address>,
-    ) -> anyhow::Result<watch::Receiver<BTreeMap<Address, u128>>> {
-        let window = Duration::days(28);
-        let (tx, rx) = watch::channel(Default::default());
-        let db = DB::spawn(window, tx);
-        let mut consumer = consumer(config)?;
+        dead_letters: DeadLetters,
+    ) -> anyhow::Result<Receipts> {
+        anyhow::ensure!(
+            !config.commit_offsets || config.receipts_snapshot.is_some(),
+            "commit_offsets requires receipts_snapshot"
+        );
+        let buckets = Buckets::new(config)?;
+        let (fees_tx, fees_rx) = watch::channel(Default::default());
+        let (breakdown_tx, breakdown_rx) = match config.receipts_breakdown {
+            true => {
+                let (tx, rx) = watch::channel(BTreeMap::new());
+                (Some(tx), Some(rx))
+            }
+            false => (None, None),
+        };
+        let consumer = Arc::new(consumer(config, !config.commit_offsets)?);
+        let (commits_tx, commits_rx) = match config.commit_offsets {
+            true => {
+                let (tx, rx) = watch::channel(BTreeMap::new());
+                (Some(tx), Some(rx))
+            }
+            false => (None, None),
+        };
 
-        let start_timestamp = hourly_timestamp(Utc::now() - window);
-        if let Some(aggregated_topic) = &config.aggregated_topic {
-            let latest_aggregated_messages =
-                latest_messages(&consumer, &[aggregated_topic]).await?;
-            let mut latest_aggregated_offsets: BTreeMap<String, i64> = latest_aggregated_messages
-                .into_iter()
-                .map(|msg| (format!("{}/{}", msg.topic(), msg.partition()), msg.offset()))
-                .collect();
-            assign_partitions(&consumer, &[aggregated_topic], start_timestamp).await?;
-            let mut latest_aggregated_timestamp = 0;
-            let mut stream = consumer.stream();
-            while let Some(msg) = stream.next().await {
-                let msg = msg?;
-                let partition = format!("{}/{}", msg.topic(), msg.partition());
-                let offset = msg.offset();
-                let payload = msg
-                    .payload()
-                    .with_context(|| anyhow!("missing payload at {partition} {offset}"))?;
-                let msg = IndexerFeesHourlyProtobuf::decode(payload)?;
-                latest_aggregated_timestamp = latest_aggregated_timestamp.max(msg.timestamp);
-                if let Some(cutoff) = config.aggregated_cutoff_timestamp {
-                    if msg.timestamp < cutoff {
-                        continue;
-                    }
-                }
-                for aggregation in &msg.aggregations {
-                    if !signers.contains(&Address::from_slice(&aggregation.signer)) {
-                        continue;
+        let snapshot = match &config.receipts_snapshot {
+            Some(snapshot_config) => Snapshot::load(
+                snapshot_config,
+                &config.realtime_topic,
+                &signers,
+                config.receipts_breakdown,
+                &buckets,
+            )
+            .unwrap_or_else(|snapshot_err| {
+                tracing::warn!("{:#}", snapshot_err.context("receipts snapshot"));
+                None
+            }),
+            None => None,
+        };
+        let resume = snapshot
+            .as_ref()
+            .map(|s| (s.timestamp, s.offsets.clone(), s.realtime_start));
+        let outputs = Outputs {
+            fees: fees_tx,
+            breakdown: breakdown_tx,
+            commits: commits_tx,
+        };
+        let db = DB::spawn(
+            buckets,
+            outputs,
+            signers.clone(),
+            config.realtime_topic.clone(),
+            config.receipts_snapshot.clone(),
+            snapshot,
+        );
+        let (positions, realtime_start) = match resume {
+            Some((timestamp, offsets, realtime_start)) => {
+                tracing::info!(timestamp, "resuming receipts from snapshot");
+                let positions = assign_offsets(
+                    &consumer,
+                    &config.realtime_topic,
+                    &offsets,
+                    realtime_start,
+                    config.commit_offsets,
+                )?;
+                (positions, realtime_start)
+            }
+            None => {
+                if config.commit_offsets {
+                    // The committed offsets only cover messages applied to a persisted DB, so
+                    // they can't be resumed from once the DB has to be rebuilt.
+                    let partitions = partitions(&consumer, &config.realtime_topic)?;
+                    let committed =
+                        committed_offsets(&consumer, &config.realtime_topic, &partitions)?;
+                    if !committed.is_empty() {
+                        tracing::info!(
+                            ?committed,
+                            "no receipts snapshot to resume from, ignoring committed offsets"
+                        );
                     }
-                    let update = Update {
-                        timestamp: DateTime::from_timestamp_millis(msg.timestamp)
-                            .context("timestamp out of range")?,
-                        indexer: Address::from_slice(&aggregation.receiver),
-                        fee: (aggregation.fee_grt * 1e18) as u128,
-                    };
-                    db.send(update).await.unwrap();
                 }
-
-                if latest_aggregated_offsets.get(&partition).unwrap() == &offset {
-                    latest_aggregated_offsets.remove(&partition);
-                    if latest_aggregated_offsets.is_empty() {
-                        break;
+                let realtime_start =
+                    replay(config, &consumer, &db, &signers, &buckets, &dead_letters).await?;
+                (BTreeMap::new(), realtime_start)
+            }
+        };
+        if let Some(mut commits) = commits_rx {
+            let consumer = consumer.clone();
+            let topic = config.realtime_topic.clone();
+            tokio::spawn(async move {
+                while commits.changed().await.is_ok() {
+                    let offsets = commits.borrow_and_update().clone();
+                    if let Err(commit_err) = commit(&consumer, &topic, &offsets) {
+                        tracing::warn!(%commit_err);
                     }
                 }
-            }
-            consumer.unassign()?;
-            let realtime_start =
-                latest_aggregated_timestamp + Duration::hours(1).num_milliseconds();
-            assign_partitions(&consumer, &[&config.realtime_topic], realtime_start).await?;
-        } else {
-            assign_partitions(&consumer, &[&config.realtime_topic], start_timestamp).await?;
+            });
         }
+        let freshness = Freshness::new(&config.realtime_topic);
+        freshness.expect_partitions(partitions(&consumer, &config.realtime_topic)?);
+        freshness.monitor(consumer.clone());
+        let progress = freshness.clone();
+        let topic = config.realtime_topic.clone();
         tokio::spawn(async move {
-            if let Err(kafka_consumer_err) = process_messages(&mut consumer, db, signers).await {
-                tracing::error!(%kafka_consumer_err);
+            let mut supervisor = Supervisor::new(progress.clone());
+            let mut positions = positions;
+            let mut restart = false;
+            loop {
+                supervisor
+                    .run(async {
+                        if restart {
+                            // Resume after the last message applied to the DB.
+                            assign_offsets(&consumer, &topic, &positions, realtime_start, false)?;
+                            progress.set_healthy(true);
+                        }
+                        process_messages(
+                            &consumer,
+                            &db,
+                            &signers,
+                            &dead_letters,
+                            &progress,
+                            &mut positions,
+                        )
+                        .await
+                    })
+                    .await;
+                restart = true;
             }
         });
 
-        Ok(rx)
+        Ok(Receipts {
+            fees: fees_rx,
+            breakdown: breakdown_rx,
+            freshness,
+        })
+    }
+
+    /// Replay the aggregated topic, if any, and then assign the realtime topic from the end of the
+    /// aggregated data, or from the start of the window. Returns the realtime start timestamp.
+    async fn replay(
+        config: &config::Kafka,
+        consumer: &StreamConsumer,
+        db: &mpsc::Sender<Input>,
+        signers: &[Address],
+        buckets: &Buckets,
+        dead_letters: &DeadLetters,
+    ) -> anyhow::Result<i64> {
+        // The aggregated topic is hourly, so the replay starts at the beginning of an hour.
+        let start_timestamp = hourly_timestamp(Utc::now() - buckets.window) * 1_000;
+        let mut realtime_start = start_timestamp;
+        if let Some(aggregated_topic) = &config.aggregated_topic {
+            let latest_aggregated_timestamp = bootstrap(
+                config,
+                aggregated_topic,
+                start_timestamp,
+                db,
+                signers,
+                buckets,
+                dead_letters,
+            )
+            .await
+            .with_context(|| format!("replay {aggregated_topic}"))?;
+            if let Some(timestamp) = latest_aggregated_timestamp {
+                realtime_start = timestamp + Duration::hours(1).num_milliseconds();
+            }
+        }
+        assign_partitions(consumer, &[&config.realtime_topic], realtime_start).await?;
+        db.send(Input::Assigned { realtime_start }).await.unwrap();
+        Ok(realtime_start)
+    }
+
+    /// Replay the aggregated topic from `start_timestamp` up to the end of each partition, with a
+    /// dedicated consumer. A partition is done once its high watermark at the start, or its end,
+    /// is reached, so that empty and compacted partitions don't hold up the replay. Returns the
+    /// latest hourly timestamp, if any.
+    async fn bootstrap(
+        config: &config::Kafka,
+        topic: &str,
+        start_timestamp: i64,
+        db: &mpsc::Sender<Input>,
+        signers: &[Address],
+        buckets: &Buckets,
+        dead_letters: &DeadLetters,
+    ) -> anyhow::Result<Option<i64>> {
+        let consumer = consumer(config, false)?;
+        let timeout = std::time::Duration::from_secs(config.bootstrap_timeout_seconds);
+        let deadline = tokio::time::Instant::now() + timeout;
+        assign_partitions(&consumer, &[topic], start_timestamp).await?;
+        let mut ends: BTreeMap<i32, i64> = BTreeMap::new();
+        // Messages left to replay per partition, estimated from the watermarks until the first
+        // message of the partition is received
+        let mut remaining: BTreeMap<i32, i64> = BTreeMap::new();
+        for partition in partitions(&consumer, topic)? {
+            let (low, high) = consumer
+                .fetch_watermarks(topic, partition, std::time::Duration::from_secs(30))
+                .with_context(|| format!("fetch watermarks of partition {partition}"))?;
+            ends.insert(partition, high);
+            remaining.insert(partition, high - low);
+        }
+        let mut latest_timestamp = None;
+        let mut last_progress = std::time::Instant::now();
+        let mut stream = consumer.stream();
+        while remaining.values().any(|r| *r > 0) {
+            let msg = match tokio::time::timeout_at(deadline, stream.next()).await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => anyhow::bail!("stream ended"),
+                Err(_) => anyhow::bail!(
+                    "timed out after {}s, messages remaining by partition: {remaining:?}",
+                    timeout.as_secs()
+                ),
+            };
+            let (partition, left) = match msg {
+                Ok(msg) => {
+                    match aggregated_updates(
+                        &msg,
+                        config.aggregated_cutoff_timestamp,
+                        signers,
+                        buckets,
+                    ) {
+                        Ok((timestamp, updates)) => {
+                            latest_timestamp = latest_timestamp.max(Some(timestamp));
+                            for update in updates {
+                                db.send(Input::Update(update)).await.unwrap();
+                            }
+                        }
+                        Err(message_err) => reject(&msg, message_err, dead_letters),
+                    };
+                    let end = ends.get(&msg.partition()).copied().unwrap_or(0);
+                    (msg.partition(), end - (msg.offset() + 1))
+                }
+                Err(KafkaError::PartitionEOF(partition)) => (partition, 0),
+                Err(recv_error) => {
+                    tracing::warn!(topic, %recv_error);
+                    continue;
+                }
+            };
+            let left = left.max(0);
+            remaining.insert(partition, left);
+            metrics::METRICS
+                .kafka_bootstrap_remaining
+                .with_label_values(&[topic, &partition.to_string()])
+                .set(left);
+            if last_progress.elapsed() >= std::time::Duration::from_secs(10) {
+                tracing::info!(topic, ?remaining, "replaying aggregated topic");
+                last_progress = std::time::Instant::now();
+            }
+        }
+        for partition in remaining.keys() {
+            metrics::METRICS
+                .kafka_bootstrap_remaining
+                .with_label_values(&[topic, &partition.to_string()])
+                .set(0);
+        }
+        tracing::info!(topic, latest_timestamp, "aggregated topic replayed");
+        Ok(latest_timestamp)
+    }
+
+    /// Timestamp and updates of an aggregated topic message. The updates are empty for messages
+    /// before the aggregated cutoff. With buckets shorter than an hour, the hourly fees are spread
+    /// across the buckets of the hour.
+    fn aggregated_updates(
+        msg: &BorrowedMessage,
+        cutoff: Option<i64>,
+        signers: &[Address],
+        buckets: &Buckets,
+    ) -> anyhow::Result<(i64, Vec<Update>)> {
+        let payload = msg.payload().context("missing payload")?;
+        let fees = IndexerFeesHourlyProtobuf::decode(payload)
+            .with_context(|| format!("invalid payload {}", payload.encode_hex()))?;
+        if cutoff
+            .map(|cutoff| fees.timestamp < cutoff)
+            .unwrap_or(false)
+        {
+            return Ok((fees.timestamp, vec![]));
+        }
+        let hour = DateTime::from_timestamp_millis(fees.timestamp)
+            .context("timestamp out of range")?
+            .timestamp();
+        let mut updates = vec![];
+        for aggregation in &fees.aggregations {
+            let signer = Address::from_slice(&aggregation.signer);
+            if !signers.contains(&signer) {
+                continue;
+            }
+            let fee = fee(aggregation.fee_grt, aggregation.fee_wei.as_deref())?;
+            for (start, fee) in buckets.spread_hour(hour, fee) {
+                updates.push(Update {
+                    timestamp: DateTime::from_timestamp(start, 0)
+                        .context("timestamp out of range")?,
+                    signer,
+                    indexer: Address::from_slice(&aggregation.receiver),
+                    deployment: None,
+                    allocation: None,
+                    fee,
+                });
+            }
+        }
+        Ok((fees.timestamp, updates))
     }
 
     #[derive(prost::Message)]
@@ -117,6 +830,9 @@ mod receipts {
         receiver: Vec<u8>,
         #[prost(double, tag = "3")]
         fee_grt: f64,
+        /// Exact fee in wei, as a decimal string. Takes precedence over `fee_grt` when set.
+        #[prost(string, optional, tag = "100")]
+        fee_wei: Option<String>,
     }
 
     #[derive(prost::Message)]
@@ -141,207 +857,804 @@ mod receipts {
         /// 20 bytes (address)
         #[prost(bytes, tag = "1")]
         indexer: Vec<u8>,
+        /// 32 bytes (deployment ID), if available
+        #[prost(bytes, tag = "2")]
+        deployment: Vec<u8>,
+        /// 20 bytes (address), if available
+        #[prost(bytes, tag = "3")]
+        allocation: Vec<u8>,
         #[prost(double, tag = "6")]
         fee_grt: f64,
+        /// Exact fee in wei, as a decimal string. Takes precedence over `fee_grt` when set.
+        #[prost(string, optional, tag = "100")]
+        fee_wei: Option<String>,
     }
 
+    fn commit(
+        consumer: &StreamConsumer,
+        topic: &str,
+        offsets: &BTreeMap<i32, i64>,
+    ) -> anyhow::Result<()> {
+        let mut list = TopicPartitionList::new();
+        for (partition, offset) in offsets {
+            list.add_partition_offset(topic, *partition, Offset::Offset(*offset))?;
+        }
+        consumer.commit(&list, CommitMode::Async)?;
+        Ok(())
+    }
+
+    /// Messages are processed in order, so that the consumed offsets recorded by the DB and in
+    /// `positions` only cover messages for which the updates were applied. Returns on a fatal
+    /// receive error, for the consumer to be restarted from `positions`.
     async fn process_messages(
-        consumer: &mut StreamConsumer,
-        db: mpsc::Sender<Update>,
-        signers: Vec<Address>,
+        consumer: &StreamConsumer,
+        db: &mpsc::Sender<Input>,
+        signers: &[Address],
+        dead_letters: &DeadLetters,
+        freshness: &Freshness,
+        positions: &mut BTreeMap<i32, i64>,
     ) -> anyhow::Result<()> {
-        consumer
-            .stream()
-            .for_each_concurrent(16, |msg| async {
-                let msg = match msg {
-                    Ok(msg) => msg,
-                    Err(recv_error) => {
-                        tracing::error!(%recv_error);
-                        return;
-                    }
-                };
-                let payload = match msg.payload() {
-                    Some(payload) => payload,
-                    None => return,
-                };
-                let timestamp = msg
-                    .timestamp()
-                    .to_millis()
-                    .and_then(|t| DateTime::from_timestamp(t / 1_000, (t % 1_000) as u32 * 1_000))
-                    .unwrap_or_else(Utc::now);
-                let payload = match ClientQueryProtobuf::decode(payload) {
-                    Ok(payload) => payload,
-                    Err(payload_parse_err) => {
-                        tracing::error!(%payload_parse_err, input = payload.encode_hex());
-                        return;
-                    }
-                };
-                if !signers.contains(&Address::from_slice(&payload.receipt_signer)) {
-                    return;
+        let closed = || anyhow!("receipts DB closed");
+        let mut stream = consumer.stream();
+        while let Some(msg) = stream.next().await {
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(KafkaError::PartitionEOF(partition)) => {
+                    freshness.partition_eof(partition);
+                    continue;
                 }
-                for indexer_query in payload.indexer_queries {
-                    let update = Update {
-                        timestamp,
-                        indexer: Address::from_slice(&indexer_query.indexer),
-                        fee: (indexer_query.fee_grt * 1e18) as u128,
-                    };
-                    let _ = db.send(update).await;
+                Err(recv_err) if !fatal(consumer, &recv_err) => {
+                    tracing::error!(%recv_err);
+                    continue;
                 }
+                Err(recv_err) => return Err(recv_err).context("receive"),
+            };
+            match message_updates(&msg, signers) {
+                Ok(updates) => {
+                    for update in updates {
+                        db.send(Input::Update(update)).await.map_err(|_| closed())?;
+                    }
+                }
+                Err(message_err) => reject(&msg, message_err, dead_letters),
+            };
+            let consumed = Input::Consumed {
+                partition: msg.partition(),
+                offset: msg.offset() + 1,
+            };
+            db.send(consumed).await.map_err(|_| closed())?;
+            positions.insert(msg.partition(), msg.offset() + 1);
+            freshness.processed(&msg);
+        }
+        anyhow::bail!("stream ended")
+    }
+
+    /// Updates from a realtime topic message. Messages that can't be decoded, or with an invalid
+    /// fee, are rejected as a whole.
+    fn message_updates(msg: &BorrowedMessage, signers: &[Address]) -> anyhow::Result<Vec<Update>> {
+        let payload = msg.payload().context("missing payload")?;
+        let timestamp = msg
+            .timestamp()
+            .to_millis()
+            .and_then(|t| DateTime::from_timestamp(t / 1_000, (t % 1_000) as u32 * 1_000))
+            .unwrap_or_else(Utc::now);
+        let payload = ClientQueryProtobuf::decode(payload)
+            .with_context(|| format!("invalid payload {}", payload.encode_hex()))?;
+        let signer = Address::from_slice(&payload.receipt_signer);
+        if !signers.contains(&signer) {
+            return Ok(vec![]);
+        }
+        payload
+            .indexer_queries
+            .into_iter()
+            .map(|indexer_query| {
+                Ok(Update {
+                    timestamp,
+                    signer,
+                    indexer: Address::from_slice(&indexer_query.indexer),
+                    deployment: B256::try_from(indexer_query.deployment.as_slice()).ok(),
+                    allocation: Address::try_from(indexer_query.allocation.as_slice()).ok(),
+                    fee: fee(indexer_query.fee_grt, indexer_query.fee_wei.as_deref())?,
+                })
             })
-            .await;
-        Ok(())
+            .collect()
+    }
+
+    fn reject(msg: &BorrowedMessage, message_err: anyhow::Error, dead_letters: &DeadLetters) {
+        let message_err = message_err.context("rejected message");
+        tracing::error!(
+            topic = msg.topic(),
+            partition = msg.partition(),
+            offset = msg.offset(),
+            "{message_err:#}"
+        );
+        super::reject(msg, &message_err, dead_letters);
+    }
+
+    /// Fee in wei, from the exact `fee_wei` field if set, or else from `fee_grt`.
+    fn fee(fee_grt: f64, fee_wei: Option<&str>) -> anyhow::Result<u128> {
+        match fee_wei {
+            Some(fee_wei) => fee_wei
+                .parse()
+                .with_context(|| format!("invalid fee_wei {fee_wei:?}")),
+            None => grt_to_wei(fee_grt).with_context(|| format!("invalid fee_grt {fee_grt}")),
+        }
+    }
+
+    /// Convert GRT to wei using the shortest decimal representation of the float, so that e.g.
+    /// `0.3` is exactly 3e17 wei. Digits beyond 18 decimals are truncated. NaN, infinite, negative
+    /// and out of range values are rejected.
+    fn grt_to_wei(grt: f64) -> Option<u128> {
+        if !grt.is_finite() || grt.is_sign_negative() {
+            return None;
+        }
+        // The `Display` representation of floats never uses an exponent.
+        let decimal = grt.to_string();
+        let (integer, fraction) = decimal.split_once('.').unwrap_or((&decimal, ""));
+        let fraction = format!("{:0<18}", &fraction[..fraction.len().min(18)]);
+        integer
+            .parse::<u128>()
+            .ok()?
+            .checked_mul(10_u128.pow(18))?
+            .checked_add(fraction.parse().ok()?)
+    }
+
+    pub enum Input {
+        Update(Update),
+        /// Consumption of the realtime topic starts from this timestamp, after the bootstrap
+        Assigned {
+            realtime_start: i64,
+        },
+        /// The realtime topic partition was consumed up to this offset (exclusive)
+        Consumed {
+            partition: i32,
+            offset: i64,
+        },
     }
 
     pub struct Update {
         pub timestamp: DateTime<Utc>,
+        pub signer: Address,
         pub indexer: Address,
+        pub deployment: Option<B256>,
+        pub allocation: Option<Address>,
         pub fee: u128,
     }
 
+    pub struct Outputs {
+        pub fees: watch::Sender<BTreeMap<(Address, Address), Fees>>,
+        /// Fees by breakdown key, if enabled
+        pub breakdown: Option<watch::Sender<BTreeMap<BreakdownKey, u128>>>,
+        /// Offsets persisted in the last snapshot, to be committed to the consumer group
+        pub commits: Option<watch::Sender<BTreeMap<i32, i64>>>,
+    }
+
     pub struct DB {
-        // indexer debts, aggregated per hour
-        data: BTreeMap<Address, BTreeMap<i64, u128>>,
-        window: Duration,
-        tx: watch::Sender<BTreeMap<Address, u128>>,
+        // debts by (signer, indexer), aggregated per bucket
+        data: BTreeMap<(Address, Address), BTreeMap<i64, u128>>,
+        /// Debts by breakdown key, aggregated per bucket, if enabled
+        breakdown: Option<BTreeMap<BreakdownKey, BTreeMap<i64, u128>>>,
+        /// Whether the breakdown changed since it was last sent
+        breakdown_changed: bool,
+        buckets: Buckets,
+        outputs: Outputs,
+        signers: Vec<Address>,
+        realtime_topic: String,
+        /// Start of the realtime topic consumption, once assigned
+        realtime_start: Option<i64>,
+        /// Next offset to consume per realtime topic partition
+        offsets: BTreeMap<i32, i64>,
+        snapshot_config: Option<config::ReceiptsSnapshot>,
     }
 
     impl DB {
         pub fn spawn(
-            window: Duration,
-            tx: watch::Sender<BTreeMap<Address, u128>>,
-        ) -> mpsc::Sender<Update> {
+            buckets: Buckets,
+            outputs: Outputs,
+            signers: Vec<Address>,
+            realtime_topic: String,
+            snapshot_config: Option<config::ReceiptsSnapshot>,
+            snapshot: Option<Snapshot>,
+        ) -> mpsc::Sender<Input> {
             let mut db = Self {
                 data: Default::default(),
-                window,
-                tx,
+                breakdown: outputs.breakdown.as_ref().map(|_| Default::default()),
+                breakdown_changed: true,
+                buckets,
+                outputs,
+                signers,
+                realtime_topic,
+                realtime_start: None,
+                offsets: Default::default(),
+                snapshot_config,
             };
+            if let Some(snapshot) = snapshot {
+                db.data = snapshot.data;
+                db.data.retain(|(signer, _), _| db.signers.contains(signer));
+                if let (Some(breakdown), Some(snapshot_breakdown)) =
+                    (&mut db.breakdown, snapshot.breakdown)
+                {
+                    *breakdown = snapshot_breakdown;
+                    breakdown.retain(|key, _| db.signers.contains(&key.signer));
+                }
+                db.realtime_start = Some(snapshot.realtime_start);
+                db.offsets = snapshot.offsets;
+            }
             let (tx, mut rx) = mpsc::channel(128);
             tokio::spawn(async move {
                 let mut last_snapshot = Utc::now();
+                let mut last_save = Utc::now();
                 let buffer_size = 128;
-                let mut buffer: Vec<Update> = Vec::with_capacity(buffer_size);
+                let mut buffer: Vec<Input> = Vec::with_capacity(buffer_size);
                 loop {
                     rx.recv_many(&mut buffer, buffer_size).await;
                     let now = Utc::now();
-                    for update in buffer.drain(..) {
-                        db.update(update, now);
+                    for input in buffer.drain(..) {
+                        match input {
+                            Input::Update(update) => db.update(update, now),
+                            Input::Assigned { realtime_start } => {
+                                db.realtime_start = Some(realtime_start);
+                            }
+                            Input::Consumed { partition, offset } => {
+                                db.offsets.insert(partition, offset);
+                            }
+                        }
                     }
 
                     if (now - last_snapshot) >= Duration::seconds(1) {
                         db.prune(now);
-                        let snapshot = db.snapshot();
-
-                        let _ = db.tx.send(snapshot);
+                        let _ = db.outputs.fees.send(db.fees(now));
+                        if let (Some(tx), Some(breakdown), true) =
+                            (&db.outputs.breakdown, &db.breakdown, db.breakdown_changed)
+                        {
+                            let _ = tx.send(sums(breakdown));
+                            db.breakdown_changed = false;
+                        }
                         last_snapshot = now;
                     }
+                    if let Some(snapshot_config) = &db.snapshot_config {
+                        let interval = Duration::seconds(snapshot_config.interval_seconds as i64);
+                        if (now - last_save) >= interval {
+                            if let Err(snapshot_err) = db.save(&snapshot_config.path, now).await {
+                                tracing::warn!("{:#}", snapshot_err.context("receipts snapshot"));
+                            }
+                            last_save = now;
+                        }
+                    }
                 }
             });
             tx
         }
 
+        /// Persist the DB, once the consumption of the realtime topic has started.
+        async fn save(&self, path: &Path, now: DateTime<Utc>) -> anyhow::Result<()> {
+            let realtime_start = match self.realtime_start {
+                Some(realtime_start) => realtime_start,
+                None => return Ok(()),
+            };
+            let snapshot = Snapshot {
+                timestamp: now.timestamp(),
+                realtime_topic: self.realtime_topic.clone(),
+                signers: self.signers.clone(),
+                realtime_start,
+                offsets: self.offsets.clone(),
+                bucket_seconds: self.buckets.size,
+                window_seconds: self.buckets.window.num_seconds(),
+                data: self.data.clone(),
+                breakdown: self.breakdown.clone(),
+            };
+            let path = path.to_path_buf();
+            let offsets =
+                tokio::task::spawn_blocking(move || snapshot.save(&path).map(|_| snapshot.offsets))
+                    .await??;
+            if let Some(commits) = &self.outputs.commits {
+                let _ = commits.send(offsets);
+            }
+            Ok(())
+        }
+
         fn update(&mut self, update: Update, now: DateTime<Utc>) {
-            if update.timestamp < (now - self.window) {
+            if update.timestamp < (now - self.buckets.window) {
                 return;
             }
+            let bucket = self.buckets.start(update.timestamp);
             let entry = self
                 .data
-                .entry(update.indexer)
+                .entry((update.signer, update.indexer))
                 .or_default()
-                .entry(hourly_timestamp(update.timestamp))
+                .entry(bucket)
                 .or_default();
             *entry += update.fee;
+            if let Some(breakdown) = &mut self.breakdown {
+                let key = BreakdownKey {
+                    signer: update.signer,
+                    indexer: update.indexer,
+                    deployment: update.deployment,
+                    allocation: update.allocation,
+                };
+                *breakdown.entry(key).or_default().entry(bucket).or_default() += update.fee;
+                self.breakdown_changed = true;
+            }
         }
 
         fn prune(&mut self, now: DateTime<Utc>) {
-            let min_timestamp = hourly_timestamp(now - self.window);
+            let min_timestamp = self.buckets.start(now - self.buckets.window);
             self.data.retain(|_, entries| {
                 entries.retain(|t, _| *t > min_timestamp);
                 !entries.is_empty()
             });
+            if let Some(breakdown) = &mut self.breakdown {
+                let changed = &mut self.breakdown_changed;
+                breakdown.retain(|_, entries| {
+                    let len = entries.len();
+                    entries.retain(|t, _| *t > min_timestamp);
+                    *changed |= entries.len() != len;
+                    !entries.is_empty()
+                });
+            }
         }
 
-        fn snapshot(&self) -> BTreeMap<Address, u128> {
+        /// Fees within the window, and within the short-term window rounded up to whole buckets
+        fn fees(&self, now: DateTime<Utc>) -> BTreeMap<(Address, Address), Fees> {
+            let recent_start = self
+                .buckets
+                .short_window
+                .map(|w| self.buckets.start(now - w));
             self.data
                 .iter()
-                .map(|(indexer, entries)| (*indexer, entries.values().sum()))
+                .map(|(key, entries)| {
+                    let fees = Fees {
+                        total: entries.values().sum(),
+                        recent: recent_start
+                            .map(|start| entries.range(start..).map(|(_, v)| v).sum()),
+                    };
+                    (*key, fees)
+                })
                 .collect()
         }
     }
 
+    fn sums<K: Copy + Ord>(data: &BTreeMap<K, BTreeMap<i64, u128>>) -> BTreeMap<K, u128> {
+        data.iter()
+            .map(|(key, entries)| (*key, entries.values().sum()))
+            .collect()
+    }
+
+    /// Persisted state of the DB
+    #[serde_as]
+    #[derive(Serialize, Deserialize)]
+    pub struct Snapshot {
+        /// Time the snapshot was written, in unix seconds
+        timestamp: i64,
+        realtime_topic: String,
+        /// Signers for which the fees were recorded
+        signers: Vec<Address>,
+        /// Start of the realtime topic consumption, for partitions without a consumed offset
+        realtime_start: i64,
+        /// Next offset to consume per realtime topic partition
+        offsets: BTreeMap<i32, i64>,
+        /// Bucket size of the fees, in seconds
+        #[serde(default = "default_bucket_seconds")]
+        bucket_seconds: i64,
+        /// Window of the fees, in seconds
+        #[serde(default = "default_window_seconds")]
+        window_seconds: i64,
+        /// Fees by (signer, indexer), aggregated per bucket
+        #[serde_as(as = "Vec<(_, BTreeMap<_, DisplayFromStr>)>")]
+        data: BTreeMap<(Address, Address), BTreeMap<i64, u128>>,
+        /// Fees by breakdown key, aggregated per bucket, if enabled
+        #[serde_as(as = "Option<Vec<(_, BTreeMap<_, DisplayFromStr>)>>")]
+        #[serde(default)]
+        breakdown: Option<BTreeMap<BreakdownKey, BTreeMap<i64, u128>>>,
+    }
+
+    impl Snapshot {
+        /// Load the snapshot, if it exists and can be resumed from. Otherwise, the topics have to
+        /// be replayed.
+        fn load(
+            config: &config::ReceiptsSnapshot,
+            realtime_topic: &str,
+            signers: &[Address],
+            breakdown: bool,
+            buckets: &Buckets,
+        ) -> anyhow::Result<Option<Self>> {
+            if !config.path.exists() {
+                tracing::info!("no receipts snapshot, replaying topics");
+                return Ok(None);
+            }
+            let snapshot: Self = std::fs::read_to_string(&config.path)
+                .map_err(anyhow::Error::from)
+                .and_then(|s| serde_json::from_str(&s).map_err(anyhow::Error::from))
+                .with_context(|| format!("failed to load {}", config.path.display()))?;
+            let age = Utc::now().timestamp() - snapshot.timestamp;
+            if age > config.max_age_seconds as i64 {
+                tracing::info!(
+                    age_seconds = age,
+                    "receipts snapshot too old, replaying topics"
+                );
+                return Ok(None);
+            }
+            if snapshot.realtime_topic != realtime_topic {
+                tracing::info!("receipts snapshot from another topic, replaying topics");
+                return Ok(None);
+            }
+            if !signers.iter().all(|s| snapshot.signers.contains(s)) {
+                tracing::info!("receipts snapshot missing signers, replaying topics");
+                return Ok(None);
+            }
+            if breakdown && snapshot.breakdown.is_none() {
+                tracing::info!("receipts snapshot without breakdown, replaying topics");
+                return Ok(None);
+            }
+            if snapshot.bucket_seconds != buckets.size
+                || snapshot.window_seconds < buckets.window.num_seconds()
+            {
+                tracing::info!(
+                    "receipts snapshot with other buckets or a shorter window, replaying topics"
+                );
+                return Ok(None);
+            }
+            Ok(Some(snapshot))
+        }
+
+        fn save(&self, path: &Path) -> anyhow::Result<()> {
+            crate::journal::write_json_atomic(path, self)
+                .with_context(|| format!("failed to write receipts snapshot {}", path.display()))
+        }
+    }
+
+    /// Snapshots written before the buckets were configurable are hourly over 28 days.
+    fn default_bucket_seconds() -> i64 {
+        Duration::hours(1).num_seconds()
+    }
+
+    fn default_window_seconds() -> i64 {
+        Duration::days(28).num_seconds()
+    }
+
     fn hourly_timestamp(t: DateTime<Utc>) -> i64 {
         let t = t.timestamp();
         t - (t % Duration::hours(1).num_seconds())
     }
+
+    #[cfg(test)]
+    mod tests {
+        use std::{collections::BTreeMap, path::Path};
+
+        use alloy::primitives::{address, Address};
+        use chrono::{Duration, Utc};
+
+        use super::{grt_to_wei, BreakdownKey, Buckets, Snapshot};
+        use crate::config;
+
+        const SIGNER: Address = address!("0x1111111111111111111111111111111111111111");
+        const INDEXER: Address = address!("0x2222222222222222222222222222222222222222");
+
+        fn snapshot() -> Snapshot {
+            let now = Utc::now().timestamp();
+            Snapshot {
+                timestamp: now,
+                realtime_topic: "gateway_queries".to_string(),
+                signers: vec![SIGNER],
+                realtime_start: now - 60,
+                offsets: BTreeMap::from([(0, 10), (1, 20)]),
+                bucket_seconds: 3600,
+                window_seconds: Duration::days(28).num_seconds(),
+                data: BTreeMap::from([(
+                    (SIGNER, INDEXER),
+                    BTreeMap::from([(now - 3600, u128::MAX), (now, 1)]),
+                )]),
+                breakdown: Some(BTreeMap::from([(
+                    BreakdownKey {
+                        signer: SIGNER,
+                        indexer: INDEXER,
+                        deployment: None,
+                        allocation: Some(INDEXER),
+                    },
+                    BTreeMap::from([(now, 1)]),
+                )])),
+            }
+        }
+
+        fn buckets(size: i64, window: Duration) -> Buckets {
+            Buckets {
+                size,
+                window,
+                short_window: None,
+            }
+        }
+
+        fn snapshot_config(dir: &Path) -> config::ReceiptsSnapshot {
+            config::ReceiptsSnapshot {
+                path: dir.join("receipts-snapshot.json"),
+                interval_seconds: 300,
+                max_age_seconds: 3600,
+            }
+        }
+
+        #[test]
+        fn buckets_config() {
+            let kafka = |window: u64, size: u64| -> config::Kafka {
+                serde_json::from_value(serde_json::json!({
+                    "config": {},
+                    "realtime_topic": "gateway_queries",
+                    "receipts_window_seconds": window,
+                    "receipts_bucket_seconds": size,
+                }))
+                .unwrap()
+            };
+            assert!(Buckets::new(&kafka(3600, 3600)).is_ok());
+            assert!(Buckets::new(&kafka(86400, 60)).is_ok());
+            assert!(Buckets::new(&kafka(0, 3600)).is_err());
+            assert!(Buckets::new(&kafka(1800, 3600)).is_err());
+            assert!(Buckets::new(&kafka(3600, 0)).is_err());
+            assert!(Buckets::new(&kafka(86400, 1)).is_err());
+        }
+
+        #[test]
+        fn spread_hour() {
+            let hour = 1_700_002_800;
+            assert_eq!(hour % 3600, 0);
+            let fee = 10u128.pow(18) + 1;
+            assert_eq!(
+                buckets(3600, Duration::days(1)).spread_hour(hour, fee),
+                [(hour, fee)]
+            );
+            assert_eq!(
+                buckets(7200, Duration::days(1)).spread_hour(hour, fee),
+                [(hour, fee)]
+            );
+            assert_eq!(
+                buckets(1800, Duration::days(1)).spread_hour(hour, fee),
+                [(hour, fee / 2 + 1), (hour + 1800, fee / 2)]
+            );
+            // Buckets that don't divide the hour get fees in proportion to their overlap.
+            let parts = buckets(2400, Duration::days(1)).spread_hour(hour, 3 * 10u128.pow(18));
+            let boundary = hour + 2400 - hour % 2400;
+            assert_eq!(
+                parts,
+                [
+                    (hour, (boundary - hour) as u128 * 10u128.pow(18) / 1200),
+                    (
+                        boundary,
+                        (hour + 3600 - boundary) as u128 * 10u128.pow(18) / 1200
+                    ),
+                ]
+            );
+            let parts = buckets(60, Duration::days(1)).spread_hour(hour, u128::MAX);
+            assert_eq!(parts.len(), 60);
+            assert_eq!(parts.iter().map(|(_, fee)| fee).sum::<u128>(), u128::MAX);
+        }
+
+        #[test]
+        fn snapshot_round_trip() {
+            let dir = tempfile::tempdir().unwrap();
+            let config = snapshot_config(dir.path());
+            let hourly = buckets(3600, Duration::days(28));
+            let load = |signers: &[Address], breakdown: bool, buckets: &Buckets| {
+                Snapshot::load(&config, "gateway_queries", signers, breakdown, buckets).unwrap()
+            };
+            assert!(load(&[SIGNER], true, &hourly).is_none());
+
+            let expected = snapshot();
+            expected.save(&config.path).unwrap();
+            let loaded = load(&[SIGNER], true, &hourly).unwrap();
+            assert_eq!(loaded.timestamp, expected.timestamp);
+            assert_eq!(loaded.signers, expected.signers);
+            assert_eq!(loaded.realtime_start, expected.realtime_start);
+            assert_eq!(loaded.offsets, expected.offsets);
+            assert_eq!(loaded.data, expected.data);
+            assert_eq!(loaded.breakdown, expected.breakdown);
+
+            // A shorter window can be resumed from, but not other buckets or a longer window.
+            assert!(load(&[SIGNER], true, &buckets(3600, Duration::days(7))).is_some());
+            assert!(load(&[SIGNER], true, &buckets(600, Duration::days(28))).is_none());
+            assert!(load(&[SIGNER], true, &buckets(3600, Duration::days(29))).is_none());
+            // Missing signers or breakdown
+            assert!(load(&[SIGNER, INDEXER], true, &hourly).is_none());
+            assert!(load(&[], false, &hourly).is_some());
+            Snapshot {
+                breakdown: None,
+                ..snapshot()
+            }
+            .save(&config.path)
+            .unwrap();
+            assert!(load(&[SIGNER], false, &hourly).is_some());
+            assert!(load(&[SIGNER], true, &hourly).is_none());
+
+            let other_topic = Snapshot::load(&config, "other", &[SIGNER], false, &hourly).unwrap();
+            assert!(other_topic.is_none());
+
+            Snapshot {
+                timestamp: Utc::now().timestamp() - 3601,
+                ..snapshot()
+            }
+            .save(&config.path)
+            .unwrap();
+            assert!(load(&[SIGNER], false, &hourly).is_none());
+        }
+
+        #[test]
+        fn snapshot_defaults_and_corrupt_file() {
+            let dir = tempfile::tempdir().unwrap();
+            let config = snapshot_config(dir.path());
+            let hourly = buckets(3600, Duration::days(28));
+            // Snapshots written before the buckets were configurable
+            let mut legacy = serde_json::to_value(snapshot()).unwrap();
+            let fields = legacy.as_object_mut().unwrap();
+            fields.remove("bucket_seconds");
+            fields.remove("window_seconds");
+            fields.remove("breakdown");
+            std::fs::write(&config.path, legacy.to_string()).unwrap();
+            let loaded = Snapshot::load(&config, "gateway_queries", &[SIGNER], false, &hourly)
+                .unwrap()
+                .unwrap();
+            assert_eq!(loaded.bucket_seconds, 3600);
+            assert_eq!(loaded.window_seconds, Duration::days(28).num_seconds());
+            assert_eq!(loaded.breakdown, None);
+
+            std::fs::write(&config.path, "{").unwrap();
+            assert!(Snapshot::load(&config, "gateway_queries", &[SIGNER], false, &hourly).is_err());
+        }
+
+        #[test]
+        fn grt_to_wei_exact() {
+            assert_eq!(grt_to_wei(0.0), Some(0));
+            assert_eq!(grt_to_wei(0.3), Some(300_000_000_000_000_000));
+            assert_eq!(grt_to_wei(1.000001), Some(1_000_001_000_000_000_000));
+            assert_eq!(grt_to_wei(1e-18), Some(1));
+            assert_eq!(grt_to_wei(1e-19), Some(0));
+            assert_eq!(grt_to_wei(12345.6789), Some(12_345_678_900_000_000_000_000));
+            assert_eq!(grt_to_wei(-1.0), None);
+            assert_eq!(grt_to_wei(f64::NAN), None);
+            assert_eq!(grt_to_wei(f64::INFINITY), None);
+            assert_eq!(grt_to_wei(1e30), None);
+        }
+    }
 }
 
 mod ravs {
-    use std::collections::BTreeMap;
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
 
     use alloy::primitives::Address;
     use anyhow::Context as _;
-    use futures_util::StreamExt as _;
-    use rdkafka::{consumer::StreamConsumer, Message as _};
+    use futures_util::{future, StreamExt as _, TryStreamExt as _};
+    use rdkafka::{
+        consumer::StreamConsumer, error::KafkaError, message::BorrowedMessage, Message as _,
+    };
     use titorelli::kafka::assign_partitions;
     use tokio::sync::watch;
 
-    use super::consumer;
-    use crate::config;
+    use super::{assign_offsets, consumer, fatal, partitions, reject, Freshness, Supervisor};
+    use crate::{config, dead_letter::DeadLetters};
+
+    const TOPIC: &str = "gateway_ravs";
+
+    pub struct Ravs {
+        /// Values of the latest RAVs, keyed by (signer, allocation)
+        pub values: watch::Receiver<BTreeMap<(Address, Address), u128>>,
+        /// Freshness of the RAVs topic data
+        pub freshness: Freshness,
+    }
 
     pub async fn ravs(
         config: &config::Kafka,
         signers: Vec<Address>,
-    ) -> anyhow::Result<watch::Receiver<BTreeMap<Address, u128>>> {
+        dead_letters: DeadLetters,
+    ) -> anyhow::Result<Ravs> {
         let (tx, rx) = watch::channel(Default::default());
-        let mut consumer = consumer(config)?;
-        assign_partitions(&consumer, &["gateway_ravs"], 0).await?;
-        tokio::spawn(async move { process_messages(&mut consumer, tx, signers).await });
-        Ok(rx)
+        let consumer = Arc::new(consumer(config, true)?);
+        assign_partitions(&consumer, &[TOPIC], 0).await?;
+        let freshness = Freshness::new(TOPIC);
+        freshness.expect_partitions(partitions(&consumer, TOPIC)?);
+        freshness.monitor(consumer.clone());
+        let progress = freshness.clone();
+        tokio::spawn(async move {
+            let mut supervisor = Supervisor::new(progress.clone());
+            let positions = Mutex::new(BTreeMap::new());
+            let mut restart = false;
+            loop {
+                supervisor
+                    .run(async {
+                        if restart {
+                            let positions = positions.lock().unwrap().clone();
+                            assign_offsets(&consumer, TOPIC, &positions, 0, false)?;
+                            progress.set_healthy(true);
+                        }
+                        process_messages(
+                            &consumer,
+                            &tx,
+                            &signers,
+                            &dead_letters,
+                            &progress,
+                            &positions,
+                        )
+                        .await
+                    })
+                    .await;
+                restart = true;
+            }
+        });
+        Ok(Ravs {
+            values: rx,
+            freshness,
+        })
     }
 
+    /// Returns on a fatal receive error, for the consumer to be restarted from `positions`.
     async fn process_messages(
-        consumer: &mut StreamConsumer,
-        tx: watch::Sender<BTreeMap<Address, u128>>,
-        signers: Vec<Address>,
-    ) {
+        consumer: &StreamConsumer,
+        tx: &watch::Sender<BTreeMap<(Address, Address), u128>>,
+        signers: &[Address],
+        dead_letters: &DeadLetters,
+        freshness: &Freshness,
+        positions: &Mutex<BTreeMap<i32, i64>>,
+    ) -> anyhow::Result<()> {
         consumer
             .stream()
-            .for_each_concurrent(16, |msg| async {
-                let msg = match msg {
-                    Ok(msg) => msg,
-                    Err(recv_error) => {
-                        tracing::error!(%recv_error);
-                        return;
+            .filter(|msg| {
+                let skip = match msg {
+                    Err(KafkaError::PartitionEOF(partition)) => {
+                        freshness.partition_eof(*partition);
+                        true
                     }
-                };
-                let record = match parse_record(&msg) {
-                    Ok(ParseResult::V2(record)) => record,
-                    Ok(ParseResult::V1) => return,
-                    Err(record_parse_err) => {
-                        let key = msg.key().map(String::from_utf8_lossy);
-                        let payload = msg.payload().map(String::from_utf8_lossy);
-                        tracing::error!(%record_parse_err, ?key, ?payload);
-                        return;
+                    Err(recv_err) if !fatal(consumer, recv_err) => {
+                        tracing::error!(%recv_err);
+                        true
                     }
+                    _ => false,
                 };
-                if !signers.contains(&record.signer) {
-                    return;
-                }
-                tx.send_if_modified(|map| {
-                    match map.entry(record.allocation) {
-                        std::collections::btree_map::Entry::Vacant(entry) => {
-                            entry.insert(record.value);
-                        }
-                        std::collections::btree_map::Entry::Occupied(mut entry)
-                            if *entry.get() < record.value =>
-                        {
-                            entry.insert(record.value);
-                        }
-                        _ => return false,
-                    };
-                    true
-                });
+                future::ready(!skip)
+            })
+            .try_for_each_concurrent(16, |msg| async move {
+                process_message(&msg, tx, signers, dead_letters);
+                freshness.processed(&msg);
+                let mut positions = positions.lock().unwrap();
+                let position = positions.entry(msg.partition()).or_default();
+                *position = (*position).max(msg.offset() + 1);
+                Ok(())
             })
-            .await;
+            .await
+            .context("receive")?;
+        anyhow::bail!("stream ended")
+    }
+
+    fn process_message(
+        msg: &BorrowedMessage,
+        tx: &watch::Sender<BTreeMap<(Address, Address), u128>>,
+        signers: &[Address],
+        dead_letters: &DeadLetters,
+    ) {
+        let record = match parse_record(msg) {
+            Ok(ParseResult::V2(record)) => record,
+            Ok(ParseResult::V1) => return,
+            Err(record_parse_err) => {
+                let key = msg.key().map(String::from_utf8_lossy);
+                let payload = msg.payload().map(String::from_utf8_lossy);
+                tracing::error!(%record_parse_err, ?key, ?payload);
+                reject(msg, &record_parse_err, dead_letters);
+                return;
+            }
+        };
+        if !signers.contains(&record.signer) {
+            return;
+        }
+        tx.send_if_modified(|map| {
+            match map.entry((record.signer, record.allocation)) {
+                std::collections::btree_map::Entry::Vacant(entry) => {
+                    entry.insert(record.value);
+                }
+                std::collections::btree_map::Entry::Occupied(mut entry)
+                    if *entry.get() < record.value =>
+                {
+                    entry.insert(record.value);
+                }
+                _ => return false,
+            };
+            true
+        });
     }
 
     struct Record {
@@ -355,7 +1668,7 @@ mod ravs {
         V1,
     }
 
-    fn parse_record(msg: &rdkafka::message::BorrowedMessage) -> anyhow::Result<ParseResult> {
+    fn parse_record(msg: &BorrowedMessage) -> anyhow::Result<ParseResult> {
         let key = String::from_utf8_lossy(msg.key().context("missing key")?);
         let payload = String::from_utf8_lossy(msg.payload().context("missing payload")?);
         let (signer, id) = key.split_once(':').context("malformed key")?;
@@ -0,0 +1,613 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use alloy::{
+    eips::{BlockId, BlockNumberOrTag},
+    primitives::{Address, BlockNumber, B256, U256},
+    providers::{DynProvider, Provider as _},
+    rpc::types::{Filter, Log},
+    sol_types::SolEvent as _,
+};
+use anyhow::Context as _;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::{
+    contracts::{
+        GraphTallyCollector::{self, RAVCollected},
+        PaymentsEscrow,
+    },
+    events::{escrow_event::Event, Events, ThawProtobuf, WithdrawProtobuf},
+    metrics, GRT,
+};
+
+/// Escrow activity of a receiver, as observed in the chain logs since the follower started
+#[derive(Debug, Default)]
+struct Account {
+    deposited: u128,
+    /// Tokens currently thawing
+    thawing: u128,
+    withdrawn: u128,
+    /// Tokens collected by the receiver through RAVs
+    collected: u128,
+    last_collection: Option<BlockNumber>,
+}
+
+/// Escrow activity of the payer, keyed by (collector, receiver)
+#[derive(Default)]
+struct Ledger {
+    accounts: BTreeMap<(Address, Address), Account>,
+}
+
+/// Persisted progress of the follower
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    /// Last block for which the logs were processed
+    block: BlockNumber,
+    /// `tokensCollected` of the GraphTallyCollector per collection ID, as of the last RAV
+    /// collection seen. This is required to compute the tokens collected by the next RAV of the
+    /// same collection.
+    #[serde_as(as = "BTreeMap<_, DisplayFromStr>")]
+    collections: BTreeMap<B256, u128>,
+}
+
+impl Cursor {
+    fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let cursor = std::fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| serde_json::from_str(&s).map_err(anyhow::Error::from))
+            .with_context(|| format!("failed to load log cursor {}", path.display()))?;
+        Ok(Some(cursor))
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        crate::journal::write_json_atomic(path, self)
+            .with_context(|| format!("failed to write log cursor {}", path.display()))
+    }
+}
+
+pub struct Follower {
+    pub provider: DynProvider,
+    pub chain_id: u64,
+    pub payer: Address,
+    pub payments_escrow: Address,
+    /// GraphTallyCollector, for which RAV collections are tracked
+    pub collector: Address,
+    /// Collectors of the escrow accounts to follow, including the GraphTallyCollector
+    pub collectors: Vec<Address>,
+    pub cursor: PathBuf,
+    /// Block to start from when there is no cursor yet. Defaults to the latest block.
+    pub start_block: Option<BlockNumber>,
+    /// Maximum number of blocks per `eth_getLogs` request
+    pub max_block_range: u64,
+    /// Number of blocks behind the latest block to follow
+    pub confirmations: u64,
+    /// Publisher of the thaws and withdrawals
+    pub events: Events,
+}
+
+impl Follower {
+    /// Poll the chain logs for escrow events of the payer, at each interval.
+    pub async fn spawn(self, poll_interval: Duration) -> anyhow::Result<()> {
+        let cursor = match Cursor::load(&self.cursor)? {
+            Some(cursor) => cursor,
+            None => {
+                let block = match self.start_block {
+                    Some(block) => block.saturating_sub(1),
+                    None => self
+                        .provider
+                        .get_block_number()
+                        .await?
+                        .saturating_sub(self.confirmations),
+                };
+                Cursor {
+                    block,
+                    collections: Default::default(),
+                }
+            }
+        };
+        tracing::info!(chain_id = self.chain_id, payer = %self.payer, block = cursor.block, "following escrow logs");
+        tokio::spawn(async move {
+            let mut cursor = cursor;
+            let mut ledger = Ledger::default();
+            let mut interval = interval(poll_interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                interval.tick().await;
+                if let Err(follow_err) = self.follow(&mut cursor, &mut ledger).await {
+                    tracing::warn!(
+                        block = cursor.block,
+                        "{:#}",
+                        follow_err.context("follow logs")
+                    );
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Process the logs from the cursor up to the latest confirmed block.
+    async fn follow(&self, cursor: &mut Cursor, ledger: &mut Ledger) -> anyhow::Result<()> {
+        let labels = [format!("{:?}", self.payer), self.chain_id.to_string()];
+        let latest = self
+            .provider
+            .get_block_number()
+            .await?
+            .saturating_sub(self.confirmations);
+        while cursor.block < latest {
+            let from = cursor.block + 1;
+            let to = latest.min(cursor.block + self.max_block_range);
+            let mut logs = self.logs(from, to).await?;
+            logs.sort_by_key(|log| (log.block_number, log.log_index));
+            let collected = self
+                .tokens_collected(&logs, &mut cursor.collections)
+                .await?;
+            let mut timestamps = BTreeMap::new();
+            for log in &logs {
+                let Some(event) = escrow_event(log) else {
+                    continue;
+                };
+                let timestamp = self.block_timestamp(log, &mut timestamps).await?;
+                self.events
+                    .publish_at(self.chain_id, self.payer, timestamp, event);
+            }
+            let accounts: BTreeSet<(Address, Address)> = logs
+                .iter()
+                .filter_map(|log| {
+                    apply(
+                        ledger,
+                        &labels,
+                        self.collector,
+                        &mut cursor.collections,
+                        &collected,
+                        log,
+                    )
+                })
+                .collect();
+            for (collector, receiver) in accounts {
+                let account = &ledger.accounts[&(collector, receiver)];
+                tracing::info!(
+                    block = to,
+                    %collector,
+                    %receiver,
+                    deposited = %format!("{:.6}", account.deposited as f64 * 1e-18),
+                    thawing = %format!("{:.6}", account.thawing as f64 * 1e-18),
+                    withdrawn = %format!("{:.6}", account.withdrawn as f64 * 1e-18),
+                    collected = %format!("{:.6}", account.collected as f64 * 1e-18),
+                    last_collection = ?account.last_collection,
+                    "escrow activity",
+                );
+                metrics::METRICS
+                    .thawing_grt
+                    .with_label_values(&[
+                        &format!("{receiver:?}"),
+                        &format!("{collector:?}"),
+                        &labels[0],
+                        &labels[1],
+                    ])
+                    .set(account.thawing as f64 / GRT as f64);
+            }
+            cursor.block = to;
+            cursor.save(&self.cursor)?;
+        }
+        metrics::METRICS
+            .log_block
+            .with_label_values(&labels)
+            .set(cursor.block as i64);
+        Ok(())
+    }
+
+    /// `tokensCollected` of the GraphTallyCollector after each `RAVCollected` event, keyed by
+    /// collection ID and block. The tokens collected before the first event of each collection
+    /// not seen yet are recorded in `collections`, so that they are not counted. The amount
+    /// collected may be less than the RAV value, so the tokens collected by each RAV are taken from
+    /// `tokensCollected` rather than from the RAV value.
+    async fn tokens_collected(
+        &self,
+        logs: &[Log],
+        collections: &mut BTreeMap<B256, u128>,
+    ) -> anyhow::Result<BTreeMap<(B256, BlockNumber), u128>> {
+        let collector = GraphTallyCollector::new(self.collector, &self.provider);
+        let tokens_collected = |event: &RAVCollected, block: BlockNumber| {
+            let call = collector
+                .tokensCollected(
+                    event.dataService,
+                    event.collectionId,
+                    event.serviceProvider,
+                    self.payer,
+                )
+                .block(BlockId::number(block));
+            async move {
+                call.call()
+                    .await
+                    .map(tokens)
+                    .with_context(|| format!("get tokens collected at block {block}"))
+            }
+        };
+        let mut collected = BTreeMap::new();
+        for log in logs {
+            if log.topic0() != Some(&RAVCollected::SIGNATURE_HASH) {
+                continue;
+            }
+            let (Ok(event), Some(block)) = (log.log_decode::<RAVCollected>(), log.block_number)
+            else {
+                continue;
+            };
+            let event = event.inner.data;
+            if let Entry::Vacant(entry) = collections.entry(event.collectionId) {
+                entry.insert(tokens_collected(&event, block.saturating_sub(1)).await?);
+            }
+            if let Entry::Vacant(entry) = collected.entry((event.collectionId, block)) {
+                entry.insert(tokens_collected(&event, block).await?);
+            }
+        }
+        Ok(collected)
+    }
+
+    /// Time of the block of the log, from the log if the provider includes it, or from the block
+    /// header otherwise. Header timestamps are cached in `timestamps` by block number.
+    async fn block_timestamp(
+        &self,
+        log: &Log,
+        timestamps: &mut BTreeMap<BlockNumber, u64>,
+    ) -> anyhow::Result<DateTime<Utc>> {
+        let block = log.block_number.context("log without block number")?;
+        let timestamp = match (log.block_timestamp, timestamps.entry(block)) {
+            (Some(timestamp), _) => timestamp,
+            (None, Entry::Occupied(entry)) => *entry.get(),
+            (None, Entry::Vacant(entry)) => {
+                let header = self
+                    .provider
+                    .get_block_by_number(BlockNumberOrTag::Number(block))
+                    .await
+                    .with_context(|| format!("get block {block}"))?
+                    .with_context(|| format!("missing block {block}"))?
+                    .header;
+                *entry.insert(header.timestamp)
+            }
+        };
+        DateTime::from_timestamp(timestamp as i64, 0)
+            .with_context(|| format!("invalid timestamp of block {block}"))
+    }
+
+    async fn logs(&self, from: BlockNumber, to: BlockNumber) -> anyhow::Result<Vec<Log>> {
+        let escrow_filter = Filter::new()
+            .address(self.payments_escrow)
+            .event_signature(vec![
+                PaymentsEscrow::Deposit::SIGNATURE_HASH,
+                PaymentsEscrow::Thaw::SIGNATURE_HASH,
+                PaymentsEscrow::CancelThaw::SIGNATURE_HASH,
+                PaymentsEscrow::Withdraw::SIGNATURE_HASH,
+            ])
+            .topic1(self.payer.into_word())
+            .topic2(
+                self.collectors
+                    .iter()
+                    .map(|c| c.into_word())
+                    .collect::<Vec<B256>>(),
+            )
+            .from_block(from)
+            .to_block(to);
+        let collector_filter = Filter::new()
+            .address(self.collector)
+            .event_signature(RAVCollected::SIGNATURE_HASH)
+            .topic2(self.payer.into_word())
+            .from_block(from)
+            .to_block(to);
+        let (escrow_logs, collector_logs) = tokio::try_join!(
+            self.provider.get_logs(&escrow_filter),
+            self.provider.get_logs(&collector_filter),
+        )
+        .with_context(|| format!("get logs for blocks {from}-{to}"))?;
+        Ok(escrow_logs.into_iter().chain(collector_logs).collect())
+    }
+}
+
+/// Apply an escrow event to the ledger, returning the (collector, receiver) it affects. `collected`
+/// holds the `tokensCollected` after the `RAVCollected` events, keyed by collection ID and block.
+fn apply(
+    ledger: &mut Ledger,
+    labels: &[String; 2],
+    collector: Address,
+    collections: &mut BTreeMap<B256, u128>,
+    collected: &BTreeMap<(B256, BlockNumber), u128>,
+    log: &Log,
+) -> Option<(Address, Address)> {
+    let block = log.block_number;
+    let result = match *log.topic0()? {
+        PaymentsEscrow::Deposit::SIGNATURE_HASH => {
+            log.log_decode::<PaymentsEscrow::Deposit>().map(|log| {
+                let event = log.inner.data;
+                let account = ledger
+                    .accounts
+                    .entry((event.collector, event.receiver))
+                    .or_default();
+                account.deposited = account.deposited.saturating_add(tokens(event.tokens));
+                (event.collector, event.receiver)
+            })
+        }
+        PaymentsEscrow::Thaw::SIGNATURE_HASH => {
+            log.log_decode::<PaymentsEscrow::Thaw>().map(|log| {
+                let event = log.inner.data;
+                // The event carries the total amount thawing, which replaces any previous thaw.
+                ledger
+                    .accounts
+                    .entry((event.collector, event.receiver))
+                    .or_default()
+                    .thawing = tokens(event.tokens);
+                (event.collector, event.receiver)
+            })
+        }
+        PaymentsEscrow::CancelThaw::SIGNATURE_HASH => {
+            log.log_decode::<PaymentsEscrow::CancelThaw>().map(|log| {
+                let event = log.inner.data;
+                ledger
+                    .accounts
+                    .entry((event.collector, event.receiver))
+                    .or_default()
+                    .thawing = 0;
+                (event.collector, event.receiver)
+            })
+        }
+        PaymentsEscrow::Withdraw::SIGNATURE_HASH => {
+            log.log_decode::<PaymentsEscrow::Withdraw>().map(|log| {
+                let event = log.inner.data;
+                let account = ledger
+                    .accounts
+                    .entry((event.collector, event.receiver))
+                    .or_default();
+                account.withdrawn = account.withdrawn.saturating_add(tokens(event.tokens));
+                account.thawing = 0;
+                (event.collector, event.receiver)
+            })
+        }
+        RAVCollected::SIGNATURE_HASH => log.log_decode::<RAVCollected>().map(|log| {
+            let event = log.inner.data;
+            let total = block
+                .and_then(|block| collected.get(&(event.collectionId, block)))
+                .copied()
+                .unwrap_or(event.valueAggregate);
+            let previous = collections.insert(event.collectionId, total).unwrap_or(0);
+            let collected = total.saturating_sub(previous);
+            let account = ledger
+                .accounts
+                .entry((collector, event.serviceProvider))
+                .or_default();
+            account.collected = account.collected.saturating_add(collected);
+            account.last_collection = block;
+            let receiver = format!("{:?}", event.serviceProvider);
+            metrics::METRICS
+                .ravs_collected
+                .with_label_values(&[&receiver, &labels[0], &labels[1]])
+                .inc();
+            metrics::METRICS
+                .rav_collected_grt
+                .with_label_values(&[&receiver, &labels[0], &labels[1]])
+                .inc_by(collected as f64 / GRT as f64);
+            (collector, event.serviceProvider)
+        }),
+        _ => return None,
+    };
+    match result {
+        Ok(account) => Some(account),
+        Err(decode_err) => {
+            tracing::warn!(?block, tx_hash = ?log.transaction_hash, %decode_err, "invalid escrow log");
+            None
+        }
+    }
+}
+
+/// Escrow event to publish for a thaw or withdrawal log
+fn escrow_event(log: &Log) -> Option<Event> {
+    let tx_hash = log.transaction_hash.unwrap_or_default().to_vec();
+    let block_number = log.block_number.unwrap_or_default();
+    let log_index = log.log_index.unwrap_or_default();
+    let event = match *log.topic0()? {
+        PaymentsEscrow::Thaw::SIGNATURE_HASH => {
+            let event = log.log_decode::<PaymentsEscrow::Thaw>().ok()?.inner.data;
+            Event::Thaw(ThawProtobuf {
+                collector: event.collector.to_vec(),
+                receiver: event.receiver.to_vec(),
+                tokens_wei: event.tokens.to_string(),
+                thaw_end_timestamp: event.thawEndTimestamp.try_into().unwrap_or(u64::MAX),
+                tx_hash,
+                block_number,
+                log_index,
+            })
+        }
+        PaymentsEscrow::CancelThaw::SIGNATURE_HASH => {
+            let event = log
+                .log_decode::<PaymentsEscrow::CancelThaw>()
+                .ok()?
+                .inner
+                .data;
+            Event::Thaw(ThawProtobuf {
+                collector: event.collector.to_vec(),
+                receiver: event.receiver.to_vec(),
+                tokens_wei: "0".to_string(),
+                thaw_end_timestamp: 0,
+                tx_hash,
+                block_number,
+                log_index,
+            })
+        }
+        PaymentsEscrow::Withdraw::SIGNATURE_HASH => {
+            let event = log
+                .log_decode::<PaymentsEscrow::Withdraw>()
+                .ok()?
+                .inner
+                .data;
+            Event::Withdraw(WithdrawProtobuf {
+                collector: event.collector.to_vec(),
+                receiver: event.receiver.to_vec(),
+                tokens_wei: event.tokens.to_string(),
+                tx_hash,
+                block_number,
+                log_index,
+            })
+        }
+        _ => return None,
+    };
+    Some(event)
+}
+
+fn tokens(value: U256) -> u128 {
+    value.try_into().unwrap_or(u128::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use alloy::{
+        primitives::{address, Address, Bytes, B256, U256},
+        rpc::types::Log,
+        sol_types::SolEvent,
+    };
+
+    use super::{apply, Ledger};
+    use crate::{
+        contracts::{GraphTallyCollector::RAVCollected, PaymentsEscrow},
+        GRT,
+    };
+
+    const PAYER: Address = address!("0x1111111111111111111111111111111111111111");
+    const COLLECTOR: Address = address!("0x2222222222222222222222222222222222222222");
+    const RECEIVER: Address = address!("0x3333333333333333333333333333333333333333");
+
+    fn log<E: SolEvent>(block: u64, event: E) -> Log {
+        Log {
+            inner: alloy::primitives::Log {
+                address: Address::ZERO,
+                data: event.encode_log_data(),
+            },
+            block_number: Some(block),
+            ..Default::default()
+        }
+    }
+
+    fn rav(block: u64, collection: B256, value: u128) -> Log {
+        log(
+            block,
+            RAVCollected {
+                collectionId: collection,
+                payer: PAYER,
+                serviceProvider: RECEIVER,
+                dataService: Address::ZERO,
+                timestampNs: 0,
+                valueAggregate: value,
+                metadata: Bytes::new(),
+                signature: Bytes::new(),
+            },
+        )
+    }
+
+    #[test]
+    fn apply_escrow_events() {
+        let labels = [format!("{PAYER:?}"), "42161".to_string()];
+        let mut ledger = Ledger::default();
+        let mut collections = BTreeMap::new();
+        let collected = BTreeMap::new();
+        let mut apply = |log: Log| {
+            apply(
+                &mut ledger,
+                &labels,
+                COLLECTOR,
+                &mut collections,
+                &collected,
+                &log,
+            )
+        };
+        let account = Some((COLLECTOR, RECEIVER));
+        let tokens = |grt: u128| U256::from(grt * GRT);
+
+        let deposit = PaymentsEscrow::Deposit {
+            payer: PAYER,
+            collector: COLLECTOR,
+            receiver: RECEIVER,
+            tokens: tokens(100),
+        };
+        assert_eq!(apply(log(1, deposit.clone())), account);
+        assert_eq!(apply(log(2, deposit)), account);
+        let thaw = PaymentsEscrow::Thaw {
+            payer: PAYER,
+            collector: COLLECTOR,
+            receiver: RECEIVER,
+            tokens: tokens(50),
+            thawEndTimestamp: U256::ZERO,
+        };
+        assert_eq!(apply(log(3, thaw.clone())), account);
+        let cancel = PaymentsEscrow::CancelThaw {
+            payer: PAYER,
+            collector: COLLECTOR,
+            receiver: RECEIVER,
+            tokensThawing: tokens(50),
+            thawEndTimestamp: U256::ZERO,
+        };
+        assert_eq!(apply(log(4, cancel)), account);
+        assert_eq!(apply(log(5, thaw)), account);
+        let withdraw = PaymentsEscrow::Withdraw {
+            payer: PAYER,
+            collector: COLLECTOR,
+            receiver: RECEIVER,
+            tokens: tokens(50),
+        };
+        assert_eq!(apply(log(6, withdraw)), account);
+
+        let account = &ledger.accounts[&(COLLECTOR, RECEIVER)];
+        assert_eq!(account.deposited, 200 * GRT);
+        assert_eq!(account.thawing, 0);
+        assert_eq!(account.withdrawn, 50 * GRT);
+    }
+
+    #[test]
+    fn apply_rav_collected_after_seed() {
+        let labels = [format!("{PAYER:?}"), "42161".to_string()];
+        let mut ledger = Ledger::default();
+        let a = B256::repeat_byte(0xa);
+        let b = B256::repeat_byte(0xb);
+        // Collection `a` was seeded with the tokens collected before the follower started.
+        let mut collections = BTreeMap::from([(a, 40 * GRT)]);
+        // The first RAV of `a` is only partially collected, so `tokensCollected` is below its
+        // value.
+        let collected =
+            BTreeMap::from([((a, 10), 48 * GRT), ((a, 11), 80 * GRT), ((b, 12), 5 * GRT)]);
+        let mut apply = |log: Log| {
+            apply(
+                &mut ledger,
+                &labels,
+                COLLECTOR,
+                &mut collections,
+                &collected,
+                &log,
+            )
+        };
+        let account = Some((COLLECTOR, RECEIVER));
+
+        assert_eq!(apply(rav(10, a, 50 * GRT)), account);
+        assert_eq!(apply(rav(11, a, 80 * GRT)), account);
+        assert_eq!(apply(rav(12, b, 5 * GRT)), account);
+        // A log that doesn't decode is ignored.
+        let mut invalid = rav(13, a, 90 * GRT);
+        invalid.inner.data = alloy::primitives::LogData::new_unchecked(
+            invalid.inner.data.topics().to_vec(),
+            Bytes::new(),
+        );
+        assert_eq!(apply(invalid), None);
+
+        let account = &ledger.accounts[&(COLLECTOR, RECEIVER)];
+        assert_eq!(account.collected, 45 * GRT);
+        assert_eq!(account.last_collection, Some(12));
+        assert_eq!(collections[&a], 80 * GRT);
+        assert_eq!(collections[&b], 5 * GRT);
+    }
+}
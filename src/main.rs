@@ -1,22 +1,37 @@
 mod config;
 mod contracts;
+mod dead_letter;
+mod events;
+mod export;
+mod journal;
 mod kafka;
+mod logs;
 mod metrics;
+mod plan;
+mod safe;
 mod subgraphs;
 
 use std::{
     collections::{BTreeMap, BTreeSet},
     io::Write as _,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    time::{Duration, Instant},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use alloy::{primitives::Address, signers::local::PrivateKeySigner};
+use alloy::{
+    primitives::{Address, BlockNumber},
+    providers::{Provider as _, ProviderBuilder},
+    signers::local::PrivateKeySigner,
+};
 use anyhow::{anyhow, Context as _};
 use axum::{http::StatusCode, routing, Router};
 use config::Config;
-use contracts::Contracts;
+use contracts::{Contracts, DepositReceipt, Executor, Paused};
+use journal::Journal;
+use plan::{Approval, Deposit, Plan, Reason};
 use prometheus::Encoder as _;
+use reqwest::Url;
 use subgraphs::{active_allocations, authorized_signers, escrow_accounts};
 use thegraph_client_subgraphs::Client as SubgraphClient;
 use tokio::{
@@ -24,6 +39,7 @@ use tokio::{
     select,
     time::{interval, MissedTickBehavior},
 };
+use tracing::Instrument as _;
 
 #[global_allocator]
 static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
@@ -31,14 +47,36 @@ static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
 const GRT: u128 = 1_000_000_000_000_000_000;
 const MIN_DEPOSIT: u128 = 2 * GRT;
 const MAX_ADJUSTMENT: u128 = 10_000 * GRT;
+const MAX_PAUSED_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+enum Command {
+    /// Maintain the escrow balances continuously.
+    Run,
+    /// Compute the escrow changes for a single cycle and write them to a plan file.
+    Plan(PathBuf),
+    /// Execute the escrow changes from a plan file.
+    Apply(PathBuf),
+    /// Send the signed transactions from an export file.
+    Broadcast(PathBuf),
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
 
-    let config_file = std::env::args()
-        .nth(1)
+    let mut args = std::env::args().skip(1);
+    let config_file = args
+        .next()
         .ok_or_else(|| anyhow!("missing config file argument"))?;
+    let command = match (args.next().as_deref(), args.next()) {
+        (None, _) => Command::Run,
+        (Some("plan"), Some(path)) => Command::Plan(path.into()),
+        (Some("apply"), Some(path)) => Command::Apply(path.into()),
+        (Some("broadcast"), Some(path)) => Command::Broadcast(path.into()),
+        _ => anyhow::bail!(
+            "usage: tap-escrow-manager <config> [plan <output> | apply <plan> | broadcast <transactions>]"
+        ),
+    };
     let config: Config = std::fs::read_to_string(config_file)
         .map_err(anyhow::Error::from)
         .and_then(|s| serde_json::from_str(&s).map_err(anyhow::Error::from))
@@ -48,79 +86,194 @@ async fn main() -> anyhow::Result<()> {
         tracing::info!("dry run mode enabled, contract calls will be skipped");
     }
 
-    let payer = PrivateKeySigner::from_bytes(&config.secret_key)?;
-    tracing::info!(payer = %payer.address());
-    let contracts = Contracts::new(
-        payer,
-        config.rpc_url.clone(),
-        config.grt_contract,
-        config.payments_escrow_contract,
-        config.graph_tally_collector_contract,
-    );
-
     let http = reqwest::Client::builder()
         .timeout(Duration::from_secs(10))
         .build()
         .unwrap();
-    let mut network_subgraph = SubgraphClient::builder(http.clone(), config.network_subgraph)
-        .with_auth_token(Some(config.query_auth.clone()))
-        .build();
+    let mut chains: Vec<Chain> = Default::default();
+    for chain_config in config.chains() {
+        let mut chain_contracts: Vec<(&config::Payer, Contracts)> = Default::default();
+        for payer_config in chain_config.payers() {
+            let contracts = payer_contracts(chain_config, payer_config)?;
+            anyhow::ensure!(
+                chain_contracts
+                    .iter()
+                    .all(|(_, c)| c.payer() != contracts.payer()),
+                "payer {} is configured more than once",
+                contracts.payer(),
+            );
+            chain_contracts.push((payer_config, contracts));
+        }
+        let chain_id = chain_contracts[0].1.chain_id().await?;
+        anyhow::ensure!(
+            chains.iter().all(|c| c.chain_id != chain_id),
+            "chain {chain_id} is configured more than once"
+        );
+        check_chain(chain_config, chain_id, &chain_contracts[0].1).await?;
 
-    let mut signers: Vec<PrivateKeySigner> = Default::default();
-    for signer in config.signers {
-        let signer = PrivateKeySigner::from_slice(signer.as_slice()).context("load signer key")?;
-        signers.push(signer);
-    }
-    let signers = signers;
+        let mut payers: Vec<Payer> = Default::default();
+        for (payer_config, contracts) in chain_contracts {
+            // Reason for skipping state-changing contract calls, if any.
+            let skip_calls = if config.dry_run {
+                Some("dry run")
+            } else if contracts.watch_only() {
+                Some("watch-only")
+            } else if matches!(command, Command::Plan(_)) {
+                Some("plan")
+            } else {
+                None
+            };
+            payers.push(Payer {
+                chain: chain_config,
+                chain_id,
+                config: payer_config,
+                contracts,
+                skip_calls,
+                signers: vec![],
+                approval: None,
+                paused: None,
+            });
+        }
 
-    if config.authorize_signers {
-        let authorized_signers = authorized_signers(&mut network_subgraph, &contracts.payer())
-            .await
-            .context("fetch authorized signers")?;
-        for signer in &signers {
-            let authorized = authorized_signers.contains(&signer.address().0.into());
-            tracing::info!(signer = %signer.address(), authorized);
-            if authorized {
-                continue;
-            }
-            if config.dry_run {
-                tracing::info!(signer = %signer.address(), "dry run: skipping authorize_signer");
-                continue;
-            }
-            match contracts.authorize_signer(signer).await {
-                Ok(()) => tracing::info!(signer = %signer.address(), "authorized"),
-                Err(err) => tracing::error!("failed to authorize signer: {err:#}"),
+        // Await the transactions that were in flight when the process last stopped, if any.
+        let mut journal_block: Option<BlockNumber> = None;
+        for payer in &payers {
+            let block = loop {
+                match payer.contracts.resolve_journal().await {
+                    Ok(block) => break block,
+                    Err(journal_err) => {
+                        tracing::warn!(
+                            chain_id,
+                            payer = %payer.contracts.payer(),
+                            "{:#}",
+                            journal_err.context("journal")
+                        );
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                    }
+                }
             };
+            journal_block = journal_block.max(block);
         }
+
+        let network_subgraph = subgraph_client(
+            http.clone(),
+            chain_config.network_subgraph.clone(),
+            &config.query_auth,
+            journal_block,
+        );
+        chains.push(Chain {
+            chain_id,
+            network_subgraph,
+            payers,
+        });
     }
 
-    let mut allowance = contracts.allowance().await?;
-    let expected_allowance = config.grt_allowance as u128 * GRT;
-    tracing::info!(allowance = allowance as f64 * 1e-18);
-    if allowance < expected_allowance {
+    if let Command::Broadcast(transactions_file) = &command {
         if config.dry_run {
-            tracing::info!(
-                expected_allowance = expected_allowance as f64 * 1e-18,
-                "dry run: skipping approve"
-            );
-        } else {
-            contracts
-                .approve(expected_allowance)
-                .await
-                .context("approve")?;
-            allowance = contracts.allowance().await?;
-            tracing::info!(allowance = allowance as f64 * 1e-18);
+            anyhow::bail!("dry run: refusing to broadcast");
         }
+        return broadcast(&chains, transactions_file).await;
+    }
+    if let Command::Apply(plan_file) = &command {
+        let plan = Plan::load(plan_file)?;
+        let payer = chains
+            .iter()
+            .filter(|c| c.chain_id == plan.chain_id)
+            .flat_map(|c| &c.payers)
+            .find(|p| p.contracts.payer() == plan.payer)
+            .with_context(|| {
+                format!(
+                    "plan payer {} is not configured on chain {}",
+                    plan.payer, plan.chain_id
+                )
+            })?;
+        if let Some(reason) = payer.skip_calls {
+            anyhow::bail!("{reason}: refusing to apply plan");
+        }
+        return apply(&payer.contracts, plan).await;
     }
 
-    let signers: Vec<Address> = signers.into_iter().map(|s| s.address()).collect();
-    let receipts = kafka::receipts(&config.kafka, signers.clone())
+    // Receipts and RAVs are routed to the payers, and so to their chains, by signer.
+    let mut signer_payers: BTreeMap<Address, (u64, Address)> = Default::default();
+    for chain in &mut chains {
+        for payer in &mut chain.payers {
+            let span = payer.span();
+            payer
+                .setup(&mut chain.network_subgraph)
+                .instrument(span)
+                .await?;
+            for signer in &payer.signers {
+                let key = (payer.chain_id, payer.contracts.payer());
+                if let Some((other_chain, other_payer)) = signer_payers.insert(*signer, key) {
+                    anyhow::bail!(
+                        "signer {signer} is configured for both payers {other_payer} on chain \
+                         {other_chain} and {} on chain {}",
+                        payer.contracts.payer(),
+                        payer.chain_id,
+                    );
+                }
+            }
+        }
+    }
+    let signers: Vec<Address> = signer_payers.into_keys().collect();
+    let dead_letters = dead_letter::DeadLetters::spawn(&config.kafka)?;
+    let events = events::Events::spawn(&config.kafka)?;
+    let receipts = kafka::receipts(&config.kafka, signers.clone(), dead_letters.clone())
         .await
         .context("failed to start receipts consumer")?;
-    let ravs = kafka::ravs(&config.kafka, signers)
+    let ravs = kafka::ravs(&config.kafka, signers, dead_letters)
         .await
         .context("failed to start RAVs consumer")?;
 
+    if let Command::Plan(plan_file) = &command {
+        anyhow::ensure!(
+            chains.iter().map(|c| c.payers.len()).sum::<usize>() == 1,
+            "plan requires a single payer"
+        );
+        let mut chain = chains.remove(0);
+        let payer = chain.payers.remove(0);
+        // Wait for the consumers to catch up before computing debts.
+        let timeout = Duration::from_secs(config.kafka.bootstrap_timeout_seconds);
+        tokio::time::timeout(timeout, async {
+            receipts.freshness.ready().await;
+            ravs.freshness.ready().await;
+        })
+        .await
+        .context("timed out waiting for the Kafka consumers to catch up")?;
+        let deposits = plan_deposits(&payer, &mut chain.network_subgraph, &receipts, &ravs).await?;
+        let plan = build_plan(&payer.contracts, payer.signers, payer.approval, deposits).await?;
+        plan.save(plan_file)?;
+        tracing::info!(deposits = plan.deposits.len(), "plan written");
+        return Ok(());
+    }
+
+    for payer in chains.iter().flat_map(|c| &c.payers) {
+        let log_follower = match &payer.config.log_follower {
+            Some(log_follower) => log_follower,
+            None => continue,
+        };
+        logs::Follower {
+            provider: ProviderBuilder::new()
+                .connect_http(payer.chain.rpc_url.clone())
+                .erased(),
+            chain_id: payer.chain_id,
+            payer: payer.contracts.payer(),
+            payments_escrow: payer.chain.payments_escrow_contract,
+            collector: payer.chain.graph_tally_collector_contract,
+            collectors: std::iter::once(payer.chain.graph_tally_collector_contract)
+                .chain(payer.config.collectors.iter().map(|c| c.address))
+                .collect(),
+            cursor: log_follower.cursor.clone(),
+            start_block: log_follower.start_block,
+            max_block_range: log_follower.max_block_range,
+            confirmations: log_follower.confirmations,
+            events: events.clone(),
+        }
+        .spawn(Duration::from_secs(config.update_interval_seconds as u64))
+        .await
+        .context("failed to start log follower")?;
+    }
+
     // Host metrics on a separate server with a port that isn't open to public requests.
     let port_metrics = config.port_metrics;
     tokio::spawn(async move {
@@ -137,7 +290,8 @@ async fn main() -> anyhow::Result<()> {
             .expect("metrics server failed");
     });
 
-    let mut interval = interval(Duration::from_secs(config.update_interval_seconds as u64));
+    let update_interval = Duration::from_secs(config.update_interval_seconds as u64);
+    let mut interval = interval(update_interval);
     interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
     let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
     loop {
@@ -146,156 +300,1001 @@ async fn main() -> anyhow::Result<()> {
             _ = tokio::signal::ctrl_c() => anyhow::bail!("exit"),
             _ = sigterm.recv() => anyhow::bail!("exit"),
         };
+        if !receipts.freshness.is_ready() || !ravs.freshness.is_ready() {
+            tracing::info!("waiting for the Kafka consumers to catch up");
+            continue;
+        }
         let loop_start = Instant::now();
 
-        let allocations = match active_allocations(&mut network_subgraph).await {
-            Ok(allocations) => allocations,
-            Err(active_allocations_err) => {
-                tracing::error!("{:#}", active_allocations_err.context("active allocations"));
-                continue;
+        for chain in &mut chains {
+            for payer in &mut chain.payers {
+                let span = payer.span();
+                payer
+                    .run_cycle(
+                        &config,
+                        update_interval,
+                        &mut chain.network_subgraph,
+                        &receipts,
+                        &ravs,
+                        &events,
+                    )
+                    .instrument(span)
+                    .await;
+            }
+        }
+
+        metrics::METRICS
+            .loop_duration
+            .observe(loop_start.elapsed().as_secs_f64());
+    }
+}
+
+/// Fail fast if the RPC doesn't serve the configured network, or if any of the configured contract
+/// addresses has no code.
+async fn check_chain(
+    chain: &config::Chain,
+    chain_id: u64,
+    contracts: &Contracts,
+) -> anyhow::Result<()> {
+    if let Some(network) = chain.network {
+        anyhow::ensure!(
+            chain_id == network.chain_id(),
+            "RPC chain ID {chain_id} does not match network {} ({})",
+            network.name(),
+            network.chain_id(),
+        );
+    }
+    let addresses = [
+        (
+            "payments_escrow_contract",
+            Some(chain.payments_escrow_contract),
+        ),
+        (
+            "graph_tally_collector_contract",
+            Some(chain.graph_tally_collector_contract),
+        ),
+        ("grt_contract", Some(chain.grt_contract)),
+        ("data_service_contract", chain.data_service_contract),
+    ];
+    for (field, address) in addresses {
+        let address = match address {
+            Some(address) => address,
+            None => continue,
+        };
+        anyhow::ensure!(
+            contracts.has_code(address).await?,
+            "{field} {address} has no contract code on chain {chain_id}"
+        );
+    }
+    Ok(())
+}
+
+/// Contracts for a payer, executing transactions according to its config
+fn payer_contracts(
+    chain: &config::Chain,
+    payer_config: &config::Payer,
+) -> anyhow::Result<Contracts> {
+    let wallet = payer_config
+        .secret_key
+        .map(|secret_key| PrivateKeySigner::from_bytes(&secret_key))
+        .transpose()
+        .context("load payer key")?;
+    let (payer, sender) = if payer_config.sponsor {
+        let payer = payer_config
+            .payer
+            .ok_or_else(|| anyhow!("sponsor requires the payer address"))?;
+        let sender = match (&payer_config.safe, &wallet, payer_config.sender) {
+            (Some(_), _, Some(_)) => anyhow::bail!("sender can't be set with safe"),
+            (Some(safe), _, None) => safe.address,
+            (None, Some(wallet), Some(sender)) => {
+                anyhow::ensure!(
+                    wallet.address() == sender,
+                    "sender must match the secret_key address"
+                );
+                sender
+            }
+            (None, Some(wallet), None) => wallet.address(),
+            (None, None, Some(sender)) => {
+                anyhow::ensure!(
+                    payer_config.export_transactions.is_some(),
+                    "sender without secret_key requires export_transactions"
+                );
+                sender
+            }
+            (None, None, None) => {
+                anyhow::bail!("sponsor requires either secret_key, safe or sender")
             }
         };
-        let mut receivers: BTreeSet<Address> = allocations.iter().map(|a| a.indexer).collect();
-        let escrow_accounts = match escrow_accounts(&mut network_subgraph, &contracts.payer()).await
-        {
-            Ok(escrow_accounts) => escrow_accounts,
-            Err(escrow_accounts_err) => {
-                if escrow_accounts_err.to_string().contains("missing block") {
-                    tracing::warn!("{:#}", escrow_accounts_err.context("escrow accounts"));
-                } else {
-                    tracing::error!("{:#}", escrow_accounts_err.context("escrow accounts"));
-                }
-                continue;
+        anyhow::ensure!(
+            !payer_config.authorize_signers,
+            "authorize_signers is not supported with sponsor"
+        );
+        (payer, sender)
+    } else {
+        anyhow::ensure!(
+            payer_config.sender.is_none(),
+            "sender is only used with sponsor"
+        );
+        let payer = match (&payer_config.safe, &wallet, payer_config.payer) {
+            (Some(safe), _, payer) => {
+                anyhow::ensure!(
+                    payer.unwrap_or(safe.address) == safe.address,
+                    "payer must match the safe address"
+                );
+                safe.address
             }
+            (None, _, Some(payer)) => payer,
+            (None, Some(wallet), None) => wallet.address(),
+            (None, None, None) => anyhow::bail!("either secret_key or payer must be configured"),
+        };
+        (payer, payer)
+    };
+    tracing::info!(%payer, %sender);
+    let executor = match (
+        &payer_config.safe,
+        &payer_config.export_transactions,
+        wallet,
+    ) {
+        (Some(_), Some(_), _) => {
+            anyhow::bail!("safe and export_transactions are mutually exclusive")
+        }
+        (Some(safe), None, signer) => {
+            tracing::info!(%payer, "safe mode enabled, transactions will be proposed to the payer safe");
+            Executor::Safe {
+                signer,
+                transaction_service: safe.transaction_service.clone(),
+                output: safe.output.clone(),
+            }
+        }
+        (None, Some(output), _) => {
+            tracing::info!(%payer, "export mode enabled, transactions will be written for offline signing");
+            Executor::Export(output.clone())
+        }
+        (None, None, Some(wallet)) => Executor::Wallet(wallet),
+        (None, None, None) => {
+            tracing::info!(%payer, "watch-only mode enabled, contract calls will be refused");
+            Executor::WatchOnly
+        }
+    };
+    let mut contracts = Contracts::new(
+        payer,
+        sender,
+        executor,
+        chain.rpc_url.clone(),
+        chain.grt_contract,
+        chain.payments_escrow_contract,
+        chain.graph_tally_collector_contract,
+    )?;
+    if let Some(journal) = &payer_config.journal {
+        contracts = contracts.with_journal(Journal::new(journal.clone()));
+    }
+    Ok(contracts)
+}
+
+/// Escrow management state of a single chain
+struct Chain<'a> {
+    chain_id: u64,
+    network_subgraph: SubgraphClient,
+    payers: Vec<Payer<'a>>,
+}
+
+/// Escrow management state of a single payer
+struct Payer<'a> {
+    chain: &'a config::Chain,
+    chain_id: u64,
+    config: &'a config::Payer,
+    contracts: Contracts,
+    /// Reason for skipping state-changing contract calls, if any.
+    skip_calls: Option<&'static str>,
+    /// Signers whose receipts and RAVs are owed by the payer
+    signers: Vec<Address>,
+    /// Allowance to approve, as found on startup
+    approval: Option<Approval>,
+    /// Time of the next deposit attempt, and the current backoff, while PaymentsEscrow is paused.
+    paused: Option<(Instant, Duration)>,
+}
+
+impl Payer<'_> {
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!("payer", chain_id = self.chain_id, payer = %self.contracts.payer())
+    }
+
+    /// Label values identifying the payer in metrics
+    fn metric_labels(&self) -> [String; 2] {
+        [
+            format!("{:?}", self.contracts.payer()),
+            self.chain_id.to_string(),
+        ]
+    }
+
+    /// Load the payer's signers, authorizing them and approving the GRT allowance if required.
+    async fn setup(&mut self, network_subgraph: &mut SubgraphClient) -> anyhow::Result<()> {
+        let config = self.config;
+        let contracts = &self.contracts;
+        let skip_calls = self.skip_calls;
+
+        let mut signers: Vec<PrivateKeySigner> = Default::default();
+        for signer in &config.signers {
+            let signer =
+                PrivateKeySigner::from_slice(signer.as_slice()).context("load signer key")?;
+            signers.push(signer);
+        }
+        let signers = signers;
+        anyhow::ensure!(
+            !config.authorize_signers || !signers.is_empty(),
+            "authorize_signers requires signer keys in signers"
+        );
+
+        let authorized_signers = if config.authorize_signers || config.signers_from_subgraph {
+            authorized_signers(network_subgraph, &contracts.payer())
+                .await
+                .context("fetch authorized signers")?
+        } else {
+            vec![]
         };
-        receivers.extend(escrow_accounts.keys());
-        tracing::debug!(receivers = receivers.len());
 
-        metrics::METRICS.receiver_count.set(receivers.len() as i64);
-        metrics::METRICS
-            .total_balance_grt
-            .set(escrow_accounts.values().sum::<u128>() as f64 / GRT as f64);
-
-        let mut indexer_ravs: BTreeMap<Address, u128> = Default::default();
-        {
-            let allocation_ravs = ravs.borrow();
-            for allocation in allocations {
-                if let Some(value) = allocation_ravs.get(&allocation.id) {
-                    *indexer_ravs.entry(allocation.indexer).or_default() += *value;
+        if config.authorize_signers {
+            for signer in &signers {
+                let authorized = authorized_signers.contains(&signer.address().0.into());
+                tracing::info!(signer = %signer.address(), authorized);
+                if authorized {
+                    continue;
                 }
+                if let Some(reason) = skip_calls {
+                    tracing::info!(signer = %signer.address(), "{reason}: skipping authorize_signer");
+                    continue;
+                }
+                match contracts.authorize_signer(signer).await {
+                    Ok(Some(_)) => tracing::info!(signer = %signer.address(), "authorized"),
+                    Ok(None) => {
+                        tracing::info!(signer = %signer.address(), "authorization proposed")
+                    }
+                    Err(err) => tracing::error!("failed to authorize signer: {err:#}"),
+                };
             }
         }
 
-        let mut debts: BTreeMap<Address, u128> = Default::default();
-        {
-            let receipts = receipts.borrow();
-            for receiver in &receivers {
-                let receipts = *receipts.get(receiver).unwrap_or(&0);
-                let ravs = *indexer_ravs.get(receiver).unwrap_or(&0);
-                let debt = u128::max(receipts, ravs);
-                debts.insert(*receiver, debt);
+        let mut allowance = contracts.allowance().await?;
+        let expected_allowance = config.grt_allowance as u128 * GRT;
+        tracing::info!(allowance = allowance as f64 * 1e-18);
+        let approval = (allowance < expected_allowance).then_some(Approval {
+            allowance,
+            amount: expected_allowance,
+        });
+        if approval.is_some() {
+            if let Some(reason) = skip_calls {
                 tracing::info!(
-                    %receiver,
-                    receipts = %format!("{:.6}", receipts as f64 * 1e-18),
-                    ravs = %format!("{:.6}", ravs as f64 * 1e-18),
+                    expected_allowance = expected_allowance as f64 * 1e-18,
+                    "{reason}: skipping approve"
                 );
-                let receiver_str = format!("{receiver:?}");
-                let balance = escrow_accounts.get(receiver).copied().unwrap_or(0);
-                metrics::METRICS
-                    .balance_grt
-                    .with_label_values(&[&receiver_str])
-                    .set(balance as f64 / GRT as f64);
-                metrics::METRICS
-                    .debt_grt
-                    .with_label_values(&[&receiver_str])
-                    .set(debt as f64 / GRT as f64);
+            } else {
+                let tx_block = contracts
+                    .approve(expected_allowance)
+                    .await
+                    .context("approve")?;
+                if tx_block.is_some() {
+                    allowance = contracts.allowance().await?;
+                    tracing::info!(allowance = allowance as f64 * 1e-18);
+                } else {
+                    tracing::info!("approval proposed");
+                }
             }
-        };
-        metrics::METRICS
-            .total_debt_grt
-            .set(debts.values().sum::<u128>() as f64 / GRT as f64);
+        }
+
+        let mut signer_addresses: BTreeSet<Address> = signers.iter().map(|s| s.address()).collect();
+        signer_addresses.extend(&config.signer_addresses);
+        if config.signers_from_subgraph {
+            signer_addresses.extend(&authorized_signers);
+        }
+        if signer_addresses.is_empty() {
+            tracing::warn!("no signers configured, all receipts and RAVs will be ignored");
+        }
+        tracing::info!(signers = ?signer_addresses);
+        self.signers = signer_addresses.into_iter().collect();
+        self.approval = approval;
+        Ok(())
+    }
 
-        let adjustments: Vec<(Address, u128)> = receivers
+    /// Bring the payer's escrow balances in line with the outstanding debts.
+    async fn run_cycle(
+        &mut self,
+        config: &Config,
+        update_interval: Duration,
+        network_subgraph: &mut SubgraphClient,
+        receipts: &kafka::Receipts,
+        ravs: &kafka::Ravs,
+        events: &events::Events,
+    ) {
+        let [payer_label, chain_label] = self.metric_labels();
+        let stale: Vec<&str> = match config.max_data_age_seconds {
+            Some(max_age) => [
+                ("receipts", receipts.freshness.age()),
+                ("RAVs", ravs.freshness.age()),
+            ]
             .into_iter()
-            .filter_map(|receiver| {
-                let balance = escrow_accounts.get(&receiver).cloned().unwrap_or(0);
-                let debt = u128::max(
-                    debts.get(&receiver).copied().unwrap_or(0),
-                    config.debts.get(&receiver).copied().unwrap_or(0) as u128 * GRT,
+            .filter(|(_, age)| age.map(|a| a.as_secs() > max_age).unwrap_or(true))
+            .map(|(data, _)| data)
+            .collect(),
+            None => vec![],
+        };
+        metrics::METRICS
+            .stale_data
+            .with_label_values(&[&payer_label, &chain_label])
+            .set(!stale.is_empty() as i64);
+        match self.contracts.resolve_journal().await {
+            Ok(None) => (),
+            Ok(Some(block)) => {
+                *network_subgraph = subgraph_client(
+                    network_subgraph.http_client.clone(),
+                    network_subgraph.subgraph_url.clone(),
+                    &config.query_auth,
+                    Some(block),
                 );
-                let next_balance = next_balance(debt);
-                let adjustment = next_balance.saturating_sub(balance);
-                if adjustment == 0 {
-                    return None;
+            }
+            Err(journal_err) => {
+                tracing::warn!("{:#}", journal_err.context("journal"));
+                return;
+            }
+        };
+
+        let mut deposits = match plan_deposits(self, network_subgraph, receipts, ravs).await {
+            Ok(deposits) => deposits,
+            Err(plan_err) => {
+                if format!("{plan_err:#}").contains("missing block") {
+                    tracing::warn!("{plan_err:#}");
+                } else {
+                    tracing::error!("{plan_err:#}");
                 }
+                return;
+            }
+        };
+        if deposits.is_empty() {
+            return;
+        }
+        if !stale.is_empty() {
+            match config.stale_deposit_cap_grt {
+                None => {
+                    tracing::warn!(?stale, "stale Kafka data, skipping deposits");
+                    return;
+                }
+                Some(cap) => {
+                    tracing::warn!(?stale, cap_grt = cap, "stale Kafka data, capping deposits");
+                    cap_deposits(&mut deposits, cap as u128 * GRT);
+                    if deposits.is_empty() {
+                        return;
+                    }
+                }
+            }
+        }
+        events.planned(self.chain_id, self.contracts.payer(), &deposits);
+
+        if let Some(reason) = self.skip_calls {
+            for deposit in &deposits {
                 tracing::info!(
-                    ?receiver,
-                    balance_grt = (balance as f64) / (GRT as f64),
-                    debt_grt = (debt as f64) / (GRT as f64),
-                    adjustment_grt = (adjustment as f64) / (GRT as f64),
+                    collector = ?deposit.collector,
+                    receiver = ?deposit.receiver,
+                    adjustment_grt = (deposit.amount as f64) / (GRT as f64),
+                    "{reason}: skipping deposit"
                 );
-                let receiver_str = format!("{receiver:?}");
+            }
+            return;
+        }
+        match self.contracts.pending().await {
+            Ok(false) => (),
+            Ok(true) => {
+                tracing::info!("previous transactions awaiting signatures, skipping deposit");
+                return;
+            }
+            Err(pending_err) => {
+                tracing::error!("{:#}", pending_err.context("pending transactions"));
+                return;
+            }
+        }
+        if let Some((retry_at, _)) = self.paused {
+            if Instant::now() < retry_at {
+                tracing::debug!("PaymentsEscrow paused, skipping deposit");
+                return;
+            }
+        }
+        let deposit_start = Instant::now();
+        let deposit_result = self
+            .contracts
+            .deposit_many(deposits.iter().map(|d| (d.collector, d.receiver, d.amount)))
+            .await;
+        metrics::METRICS
+            .deposit
+            .duration
+            .observe(deposit_start.elapsed().as_secs_f64());
+        if let Err(deposit_err) = &deposit_result {
+            events.deposit_failed(
+                self.chain_id,
+                self.contracts.payer(),
+                &deposits,
+                deposit_err,
+            );
+            if deposit_err.is::<Paused>() {
+                let backoff = match self.paused {
+                    Some((_, backoff)) => (backoff * 2).min(MAX_PAUSED_BACKOFF),
+                    None => {
+                        tracing::warn!("PaymentsEscrow is paused, backing off deposits");
+                        update_interval
+                    }
+                };
+                self.paused = Some((Instant::now() + backoff, backoff));
                 metrics::METRICS
-                    .adjustment_grt
-                    .with_label_values(&[&receiver_str])
-                    .set(adjustment as f64 / GRT as f64);
-                Some((receiver, adjustment))
-            })
+                    .paused
+                    .with_label_values(&[&payer_label, &chain_label])
+                    .set(1);
+                return;
+            }
+        }
+        if self.paused.take().is_some() {
+            tracing::info!("PaymentsEscrow is no longer paused");
+            metrics::METRICS
+                .paused
+                .with_label_values(&[&payer_label, &chain_label])
+                .set(0);
+        }
+        let receipt = match deposit_result {
+            Ok(Some(receipt)) => {
+                metrics::METRICS.deposit.ok.inc();
+                events.deposited(self.chain_id, self.contracts.payer(), &deposits, &receipt);
+                receipt
+            }
+            Ok(None) => {
+                metrics::METRICS.deposit.ok.inc();
+                tracing::info!("deposits proposed");
+                return;
+            }
+            Err(deposit_err) => {
+                metrics::METRICS.deposit.err.inc();
+                tracing::error!("{:#}", deposit_err.context("deposit"));
+                return;
+            }
+        };
+        *network_subgraph = subgraph_client(
+            network_subgraph.http_client.clone(),
+            network_subgraph.subgraph_url.clone(),
+            &config.query_auth,
+            Some(receipt.block_number),
+        );
+
+        if let Err(deposit_err) = verify_deposit(self.contracts.payer(), self.chain_id, &receipt) {
+            tracing::error!("{deposit_err:#}");
+            return;
+        }
+        tracing::info!("adjustments complete");
+    }
+}
+
+/// Client for the network subgraph, which waits for `latest_block` to be indexed if set.
+fn subgraph_client(
+    http: reqwest::Client,
+    url: Url,
+    query_auth: &str,
+    latest_block: Option<BlockNumber>,
+) -> SubgraphClient {
+    let builder = SubgraphClient::builder(http, url).with_auth_token(Some(query_auth.to_string()));
+    match latest_block {
+        Some(block) => builder.with_subgraph_latest_block(block).build(),
+        None => builder.build(),
+    }
+}
+
+/// Fees owed to a receiver
+#[derive(Clone, Copy, Default)]
+struct Fees {
+    /// Receipts within the receipts window
+    receipts: u128,
+    /// Value of the latest RAVs for the receiver's active allocations
+    ravs: u128,
+    /// Tokens already collected from escrow for those RAVs
+    collected: u128,
+    /// Value of those RAVs that has not been collected yet, summed per allocation
+    uncollected: u128,
+}
+
+impl Fees {
+    fn add_rav(&mut self, value: u128, collected: u128) {
+        self.ravs += value;
+        self.collected += collected;
+        self.uncollected += value.saturating_sub(collected);
+    }
+
+    /// Debt that has not been collected from escrow yet. Collected tokens are only subtracted from
+    /// the RAVs they were collected for, since the receipts window doesn't include older fees.
+    fn outstanding(&self) -> u128 {
+        self.receipts.max(self.uncollected)
+    }
+}
+
+/// Compute the deposits required to bring the escrow balances of all receivers in line with their
+/// outstanding debts.
+async fn plan_deposits(
+    payer: &Payer<'_>,
+    network_subgraph: &mut SubgraphClient,
+    receipts: &kafka::Receipts,
+    ravs: &kafka::Ravs,
+) -> anyhow::Result<Vec<Deposit>> {
+    let contracts = &payer.contracts;
+    let [payer_label, chain_label] = payer.metric_labels();
+    let collector = contracts.collector();
+    let collectors: BTreeSet<Address> = std::iter::once(collector)
+        .chain(payer.config.collectors.iter().map(|c| c.address))
+        .collect();
+    let mut minimum_debts: BTreeMap<(Address, Address), u128> = payer
+        .config
+        .debts
+        .iter()
+        .map(|(receiver, debt)| ((collector, *receiver), *debt as u128 * GRT))
+        .collect();
+    for c in &payer.config.collectors {
+        for (receiver, debt) in &c.debts {
+            minimum_debts.insert((c.address, *receiver), *debt as u128 * GRT);
+        }
+    }
+
+    let allocations = active_allocations(network_subgraph)
+        .await
+        .context("active allocations")?;
+    let escrow_accounts: BTreeMap<(Address, Address), u128> =
+        escrow_accounts(network_subgraph, &contracts.payer())
+            .await
+            .context("escrow accounts")?
+            .into_iter()
+            .filter(|((c, _), _)| collectors.contains(c))
             .collect();
+    // Escrow accounts to manage, keyed by (collector, receiver)
+    let mut accounts: BTreeSet<(Address, Address)> =
+        allocations.iter().map(|a| (collector, a.indexer)).collect();
+    accounts.extend(escrow_accounts.keys());
+    accounts.extend(minimum_debts.keys().filter(|(c, _)| *c != collector));
+    tracing::debug!(accounts = accounts.len());
 
-        let total_adjustment: u128 = adjustments.iter().map(|(_, a)| a).sum();
-        tracing::info!(total_adjustment_grt = ((total_adjustment as f64) * 1e-18).ceil() as u64);
+    metrics::METRICS
+        .receiver_count
+        .with_label_values(&[&payer_label, &chain_label])
+        .set(accounts.len() as i64);
+    metrics::METRICS
+        .total_balance_grt
+        .with_label_values(&[&payer_label, &chain_label])
+        .set(escrow_accounts.values().sum::<u128>() as f64 / GRT as f64);
+
+    // Latest RAV value per allocation, across the payer's signers
+    let mut allocation_values: BTreeMap<Address, u128> = Default::default();
+    for ((signer, allocation), value) in ravs.values.borrow().iter() {
+        if payer.signers.contains(signer) {
+            let entry = allocation_values.entry(*allocation).or_default();
+            *entry = (*entry).max(*value);
+        }
+    }
+    let allocation_ravs: Vec<(Address, Address, u128)> = allocations
+        .iter()
+        .filter_map(|a| Some((a.id, a.indexer, *allocation_values.get(&a.id)?)))
+        .collect();
+    let mut indexer_receipts: BTreeMap<Address, u128> = Default::default();
+    let mut signer_receipts: BTreeMap<Address, kafka::Fees> = Default::default();
+    for ((signer, indexer), fees) in receipts.fees.borrow().iter() {
+        if payer.signers.contains(signer) {
+            *indexer_receipts.entry(*indexer).or_default() += fees.total;
+            let entry = signer_receipts.entry(*signer).or_default();
+            entry.total += fees.total;
+            entry.recent = fees.recent.map(|recent| entry.recent.unwrap_or(0) + recent);
+        }
+    }
+    for signer in &payer.signers {
+        let fees = signer_receipts.get(signer).copied().unwrap_or_default();
+        let labels = [&format!("{signer:?}"), &payer_label, &chain_label];
         metrics::METRICS
-            .total_adjustment_grt
-            .set(total_adjustment as f64 / GRT as f64);
-        if total_adjustment > 0 {
-            let adjustments = if total_adjustment <= MAX_ADJUSTMENT {
-                adjustments
-            } else {
-                reduce_adjustments(adjustments)
-            };
-            if config.dry_run {
-                for (receiver, adjustment) in &adjustments {
-                    tracing::info!(
-                        ?receiver,
-                        adjustment_grt = (*adjustment as f64) / (GRT as f64),
-                        "dry run: skipping deposit"
-                    );
-                }
+            .signer_receipts_grt
+            .with_label_values(&labels)
+            .set(fees.total as f64 / GRT as f64);
+        if let Some(recent) = fees.recent {
+            metrics::METRICS
+                .signer_recent_receipts_grt
+                .with_label_values(&labels)
+                .set(recent as f64 / GRT as f64);
+        }
+    }
+    if let Some(breakdown) = &receipts.breakdown {
+        // Reconcile the receipts of active allocations with their latest RAVs.
+        let mut allocation_receipts: BTreeMap<Address, u128> = Default::default();
+        for (key, value) in breakdown.borrow().iter() {
+            if let (true, Some(allocation)) = (payer.signers.contains(&key.signer), key.allocation)
+            {
+                *allocation_receipts.entry(allocation).or_default() += *value;
+            }
+        }
+        // RAVs aggregate receipts, so a RAV above the receipts of its allocation means that
+        // receipts are missing from the Kafka topics.
+        let mut mismatches = 0;
+        for allocation in &allocations {
+            let receipts = allocation_receipts
+                .get(&allocation.id)
+                .copied()
+                .unwrap_or(0);
+            let rav = allocation_values.get(&allocation.id).copied().unwrap_or(0);
+            if receipts == 0 && rav == 0 {
                 continue;
             }
-            let deposit_start = Instant::now();
-            let deposit_result = contracts.deposit_many(adjustments).await;
-            metrics::METRICS
-                .deposit
-                .duration
-                .observe(deposit_start.elapsed().as_secs_f64());
-            let tx_block = match deposit_result {
-                Ok(block) => {
-                    metrics::METRICS.deposit.ok.inc();
-                    block
-                }
-                Err(deposit_err) => {
-                    metrics::METRICS.deposit.err.inc();
-                    tracing::error!("{:#}", deposit_err.context("deposit"));
-                    continue;
-                }
-            };
-            network_subgraph = SubgraphClient::builder(
-                network_subgraph.http_client,
-                network_subgraph.subgraph_url,
+            if rav > receipts {
+                mismatches += 1;
+                tracing::warn!(
+                    allocation = %allocation.id,
+                    indexer = %allocation.indexer,
+                    receipts = %format!("{:.6}", receipts as f64 * 1e-18),
+                    rav = %format!("{:.6}", rav as f64 * 1e-18),
+                    "allocation RAV exceeds receipts",
+                );
+            } else {
+                tracing::debug!(
+                    allocation = %allocation.id,
+                    indexer = %allocation.indexer,
+                    receipts = %format!("{:.6}", receipts as f64 * 1e-18),
+                    rav = %format!("{:.6}", rav as f64 * 1e-18),
+                    "allocation fees",
+                );
+            }
+        }
+        metrics::METRICS
+            .allocation_fee_mismatch
+            .with_label_values(&[&payer_label, &chain_label])
+            .set(mismatches);
+    }
+    let collected = match payer.chain.data_service_contract {
+        Some(data_service) => contracts
+            .tokens_collected(
+                data_service,
+                allocation_ravs
+                    .iter()
+                    .map(|(id, indexer, _)| (*id, *indexer)),
             )
-            .with_auth_token(Some(config.query_auth.clone()))
-            .with_subgraph_latest_block(tx_block)
-            .build();
+            .await
+            .context("tokens collected")?,
+        None => Default::default(),
+    };
 
-            tracing::info!("adjustments complete");
+    let mut fees: BTreeMap<Address, Fees> = Default::default();
+    for (allocation, indexer, value) in allocation_ravs {
+        fees.entry(indexer)
+            .or_default()
+            .add_rav(value, collected.get(&allocation).copied().unwrap_or(0));
+    }
+    for (_, receiver) in accounts.iter().filter(|(c, _)| *c == collector) {
+        let entry = fees.entry(*receiver).or_default();
+        entry.receipts = indexer_receipts.get(receiver).copied().unwrap_or(0);
+        tracing::info!(
+            %receiver,
+            receipts = %format!("{:.6}", entry.receipts as f64 * 1e-18),
+            ravs = %format!("{:.6}", entry.ravs as f64 * 1e-18),
+            collected = %format!("{:.6}", entry.collected as f64 * 1e-18),
+        );
+        metrics::METRICS
+            .collected_grt
+            .with_label_values(&[&format!("{receiver:?}"), &payer_label, &chain_label])
+            .set(entry.collected as f64 / GRT as f64);
+    }
+    // Fees are only tracked for the GraphTallyCollector. The debts for other collectors are their
+    // configured minimums.
+    let account_fees = |(c, receiver): (Address, Address)| -> Fees {
+        match c == collector {
+            true => fees.get(&receiver).copied().unwrap_or_default(),
+            false => Fees::default(),
         }
-
+    };
+    for account in &accounts {
+        let (c, receiver) = *account;
+        let labels = [
+            format!("{receiver:?}"),
+            format!("{c:?}"),
+            payer_label.clone(),
+            chain_label.clone(),
+        ];
+        let balance = escrow_accounts.get(account).copied().unwrap_or(0);
         metrics::METRICS
-            .loop_duration
-            .observe(loop_start.elapsed().as_secs_f64());
+            .balance_grt
+            .with_label_values(&labels)
+            .set(balance as f64 / GRT as f64);
+        metrics::METRICS
+            .debt_grt
+            .with_label_values(&labels)
+            .set(account_fees(*account).outstanding() as f64 / GRT as f64);
     }
+    metrics::METRICS
+        .total_debt_grt
+        .with_label_values(&[&payer_label, &chain_label])
+        .set(
+            accounts
+                .iter()
+                .map(|account| account_fees(*account).outstanding())
+                .sum::<u128>() as f64
+                / GRT as f64,
+        );
+
+    let mut deposits: Vec<Deposit> = accounts
+        .iter()
+        .filter_map(|account| {
+            let (collector, receiver) = *account;
+            let balance = escrow_accounts.get(account).copied().unwrap_or(0);
+            let fees = account_fees(*account);
+            let minimum_debt = minimum_debts.get(account).copied().unwrap_or(0);
+            let debt = fees.outstanding().max(minimum_debt);
+            let target_balance = next_balance(debt);
+            let adjustment = target_balance.saturating_sub(balance);
+            if adjustment == 0 {
+                return None;
+            }
+            tracing::info!(
+                ?collector,
+                ?receiver,
+                balance_grt = (balance as f64) / (GRT as f64),
+                debt_grt = (debt as f64) / (GRT as f64),
+                adjustment_grt = (adjustment as f64) / (GRT as f64),
+            );
+            metrics::METRICS
+                .adjustment_grt
+                .with_label_values(&[
+                    &format!("{receiver:?}"),
+                    &format!("{collector:?}"),
+                    &payer_label,
+                    &chain_label,
+                ])
+                .set(adjustment as f64 / GRT as f64);
+            Some(Deposit {
+                collector,
+                receiver,
+                balance,
+                receipts: fees.receipts,
+                ravs: fees.ravs,
+                collected: fees.collected,
+                minimum_debt,
+                debt,
+                target_balance,
+                amount: adjustment,
+                reason: Reason::new(fees.receipts, fees.uncollected, minimum_debt),
+                reduced: false,
+            })
+        })
+        .collect();
+
+    let total_adjustment: u128 = deposits.iter().map(|d| d.amount).sum();
+    tracing::info!(total_adjustment_grt = ((total_adjustment as f64) * 1e-18).ceil() as u64);
+    metrics::METRICS
+        .total_adjustment_grt
+        .with_label_values(&[&payer_label, &chain_label])
+        .set(total_adjustment as f64 / GRT as f64);
+    if total_adjustment > MAX_ADJUSTMENT {
+        let adjustments = deposits
+            .iter()
+            .map(|d| ((d.collector, d.receiver), d.amount))
+            .collect();
+        let reduced: BTreeMap<(Address, Address), u128> =
+            reduce_adjustments(adjustments).into_iter().collect();
+        for deposit in &mut deposits {
+            let amount = reduced[&(deposit.collector, deposit.receiver)];
+            deposit.reduced = amount < deposit.amount;
+            deposit.amount = amount;
+        }
+    }
+    Ok(deposits)
+}
+
+/// Reduce the deposits so that their total doesn't exceed `max`, for the stale data cap. Unlike
+/// `MAX_ADJUSTMENT`, this is a hard limit: deposits reduced to zero are dropped.
+fn cap_deposits(deposits: &mut Vec<Deposit>, max: u128) {
+    if deposits.iter().map(|d| d.amount).sum::<u128>() <= max {
+        return;
+    }
+    let adjustments = deposits
+        .iter()
+        .map(|d| ((d.collector, d.receiver), d.amount))
+        .collect();
+    let reduced: BTreeMap<(Address, Address), u128> =
+        cap_adjustments(adjustments, max).into_iter().collect();
+    for deposit in deposits.iter_mut() {
+        let amount = reduced[&(deposit.collector, deposit.receiver)];
+        deposit.reduced = amount < deposit.amount;
+        deposit.amount = amount;
+    }
+    deposits.retain(|d| d.amount > 0);
+}
+
+/// Check the planned deposits against the on-chain escrow balances, and record the chain state
+/// they are based on.
+async fn build_plan(
+    contracts: &Contracts,
+    signers: Vec<Address>,
+    approval: Option<Approval>,
+    deposits: Vec<Deposit>,
+) -> anyhow::Result<Plan> {
+    let chain_id = contracts.chain_id().await?;
+    let block_number = contracts.block_number().await?;
+    for deposit in &deposits {
+        let balance = contracts
+            .escrow_balance(deposit.collector, deposit.receiver, block_number)
+            .await?;
+        anyhow::ensure!(
+            balance == deposit.balance,
+            "escrow balance of {} for collector {} at block {block_number} differs from the \
+             network subgraph, retry once the subgraph has caught up",
+            deposit.receiver,
+            deposit.collector,
+        );
+    }
+    Ok(Plan {
+        version: plan::VERSION,
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        chain_id,
+        block_number,
+        payer: contracts.payer(),
+        sender: (contracts.sender() != contracts.payer()).then_some(contracts.sender()),
+        payments_escrow: contracts.payments_escrow(),
+        collector: contracts.collector(),
+        signers,
+        approval,
+        deposits,
+    })
+}
+
+/// Execute a plan, after checking that the escrow balances haven't moved since it was created.
+async fn apply(contracts: &Contracts, plan: Plan) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        plan.payer == contracts.payer(),
+        "plan payer {} does not match {}",
+        plan.payer,
+        contracts.payer(),
+    );
+    let sender = plan.sender.unwrap_or(plan.payer);
+    anyhow::ensure!(
+        sender == contracts.sender(),
+        "plan sender {sender} does not match {}",
+        contracts.sender(),
+    );
+    anyhow::ensure!(
+        (plan.payments_escrow, plan.collector)
+            == (contracts.payments_escrow(), contracts.collector()),
+        "plan contracts do not match the config",
+    );
+    let chain_id = contracts.chain_id().await?;
+    anyhow::ensure!(
+        plan.chain_id == chain_id,
+        "plan chain ID {} does not match {chain_id}",
+        plan.chain_id,
+    );
+
+    let block_number = contracts.block_number().await?;
+    for deposit in &plan.deposits {
+        let balance = contracts
+            .escrow_balance(deposit.collector, deposit.receiver, block_number)
+            .await?;
+        anyhow::ensure!(
+            balance == deposit.balance,
+            "escrow balance of {} for collector {} moved since block {} ({} -> {balance})",
+            deposit.receiver,
+            deposit.collector,
+            plan.block_number,
+            deposit.balance,
+        );
+    }
+
+    if let Some(approval) = &plan.approval {
+        let allowance = contracts.allowance().await?;
+        if allowance < approval.amount {
+            contracts
+                .approve(approval.amount)
+                .await
+                .context("approve")?;
+            tracing::info!(allowance = approval.amount as f64 * 1e-18, "approved");
+        }
+    }
+    if plan.deposits.is_empty() {
+        tracing::info!("no deposits to apply");
+        return Ok(());
+    }
+    for deposit in &plan.deposits {
+        tracing::info!(
+            collector = ?deposit.collector,
+            receiver = ?deposit.receiver,
+            adjustment_grt = (deposit.amount as f64) / (GRT as f64),
+            reason = ?deposit.reason,
+        );
+    }
+    let receipt = contracts
+        .deposit_many(
+            plan.deposits
+                .iter()
+                .map(|d| (d.collector, d.receiver, d.amount)),
+        )
+        .await
+        .context("deposit")?;
+    match receipt {
+        Some(receipt) => verify_deposit(contracts.payer(), chain_id, &receipt)?,
+        None => tracing::info!("deposits submitted for signing"),
+    };
+    tracing::info!("plan applied");
+    Ok(())
+}
+
+/// Record an executed deposit transaction, and fail if the deposited tokens differ from the planned
+/// amounts.
+fn verify_deposit(payer: Address, chain_id: u64, receipt: &DepositReceipt) -> anyhow::Result<()> {
+    let labels = [format!("{payer:?}"), chain_id.to_string()];
+    tracing::info!(
+        tx_hash = %receipt.tx_hash,
+        block = receipt.block_number,
+        gas_used = receipt.gas_used,
+        effective_gas_price = receipt.effective_gas_price,
+        "deposit executed"
+    );
+    metrics::METRICS
+        .deposit_gas_used
+        .with_label_values(&labels)
+        .set(receipt.gas_used as i64);
+    metrics::METRICS
+        .deposit_gas_price_gwei
+        .with_label_values(&labels)
+        .set(receipt.effective_gas_price as f64 * 1e-9);
+    for mismatch in &receipt.mismatches {
+        tracing::error!(
+            tx_hash = %receipt.tx_hash,
+            collector = ?mismatch.collector,
+            receiver = ?mismatch.receiver,
+            planned_grt = (mismatch.planned as f64) / (GRT as f64),
+            deposited_grt = (mismatch.deposited as f64) / (GRT as f64),
+            "deposit mismatch"
+        );
+    }
+    metrics::METRICS
+        .deposit_mismatch
+        .with_label_values(&labels)
+        .inc_by(receipt.mismatches.len() as u64);
+    anyhow::ensure!(
+        receipt.mismatches.is_empty(),
+        "deposits in {} differ from the planned amounts for {} escrow accounts",
+        receipt.tx_hash,
+        receipt.mismatches.len(),
+    );
+    Ok(())
+}
+
+/// Send the signed transactions from an export file, in nonce order. Transactions with a nonce that
+/// has already been used are skipped, so that the command can be repeated after a failure. Each
+/// signed transaction is checked against its exported fields and sender before anything is sent.
+async fn broadcast(chains: &[Chain<'_>], transactions_file: &Path) -> anyhow::Result<()> {
+    let mut txs = export::load(transactions_file)?;
+    anyhow::ensure!(!txs.is_empty(), "no transactions to broadcast");
+    for tx in &txs {
+        tx.signed()
+            .with_context(|| format!("invalid signed transaction for {} {}", tx.label, tx.nonce))?;
+    }
+    txs.sort_by_key(|tx| (tx.chain_id, tx.from, tx.nonce));
+    for tx in txs {
+        // Any payer's contracts can send the raw transactions of its chain.
+        let contracts = &chains
+            .iter()
+            .find(|c| c.chain_id == tx.chain_id)
+            .with_context(|| format!("transaction chain ID {} is not configured", tx.chain_id))?
+            .payers[0]
+            .contracts;
+        let raw = tx.signed()?;
+        if tx.nonce < contracts.transaction_count(tx.from).await? {
+            tracing::info!(
+                label = tx.label,
+                nonce = tx.nonce,
+                "already broadcast, skipping"
+            );
+            continue;
+        }
+        let tx_block = contracts
+            .broadcast(raw)
+            .await
+            .with_context(|| format!("broadcast {} {}", tx.label, tx.nonce))?;
+        tracing::info!(label = tx.label, nonce = tx.nonce, tx_block, "broadcast");
+    }
+    Ok(())
 }
 
 fn next_balance(debt: u128) -> u128 {
@@ -308,11 +1307,10 @@ fn next_balance(debt: u128) -> u128 {
     next_round as u128 * GRT
 }
 
-fn reduce_adjustments(adjustments: Vec<(Address, u128)>) -> Vec<(Address, u128)> {
-    let desired: BTreeMap<Address, u128> = adjustments.into_iter().collect();
+fn reduce_adjustments<K: Copy + Ord>(adjustments: Vec<(K, u128)>) -> Vec<(K, u128)> {
+    let desired: BTreeMap<K, u128> = adjustments.into_iter().collect();
     assert!(desired.values().sum::<u128>() > MAX_ADJUSTMENT);
-    let mut adjustments: BTreeMap<Address, u128> =
-        desired.keys().map(|r| (*r, MIN_DEPOSIT)).collect();
+    let mut adjustments: BTreeMap<K, u128> = desired.keys().map(|r| (*r, MIN_DEPOSIT)).collect();
     loop {
         for (receiver, desired_value) in &desired {
             let adjustment_value = adjustments.entry(*receiver).or_default();
@@ -326,6 +1324,29 @@ fn reduce_adjustments(adjustments: Vec<(Address, u128)>) -> Vec<(Address, u128)>
     }
 }
 
+/// Share `max` between the adjustments, none of which exceeds its desired value. The total is
+/// handed out in rounds: up to `MIN_DEPOSIT` each in the first round, then up to 100 GRT each.
+fn cap_adjustments<K: Copy + Ord>(adjustments: Vec<(K, u128)>, max: u128) -> Vec<(K, u128)> {
+    let desired: BTreeMap<K, u128> = adjustments.into_iter().collect();
+    assert!(desired.values().sum::<u128>() > max);
+    let mut adjustments: BTreeMap<K, u128> = desired.keys().map(|r| (*r, 0)).collect();
+    let mut remaining = max;
+    let mut step = MIN_DEPOSIT;
+    while remaining > 0 {
+        for (receiver, desired_value) in &desired {
+            let adjustment_value = adjustments.entry(*receiver).or_default();
+            let increment = desired_value
+                .saturating_sub(*adjustment_value)
+                .min(step)
+                .min(remaining);
+            *adjustment_value += increment;
+            remaining -= increment;
+        }
+        step = 100 * GRT;
+    }
+    adjustments.into_iter().collect()
+}
+
 async fn handle_metrics() -> impl axum::response::IntoResponse {
     let encoder = prometheus::TextEncoder::new();
     let metric_families = prometheus::gather();
@@ -341,7 +1362,111 @@ async fn handle_metrics() -> impl axum::response::IntoResponse {
 
 #[cfg(test)]
 mod tests {
-    use super::{GRT, MIN_DEPOSIT};
+    use alloy::primitives::Address;
+
+    use super::{cap_deposits, reduce_adjustments, Fees, GRT, MAX_ADJUSTMENT, MIN_DEPOSIT};
+    use crate::plan::{Deposit, Reason};
+
+    fn deposits(amounts: &[u128]) -> Vec<Deposit> {
+        amounts
+            .iter()
+            .enumerate()
+            .map(|(i, amount)| Deposit {
+                collector: Address::ZERO,
+                receiver: Address::with_last_byte(i as u8),
+                balance: 0,
+                receipts: 0,
+                ravs: 0,
+                collected: 0,
+                minimum_debt: 0,
+                debt: 0,
+                target_balance: *amount,
+                amount: *amount,
+                reason: Reason::MinimumBalance,
+                reduced: false,
+            })
+            .collect()
+    }
+
+    fn amounts(deposits: &[Deposit]) -> Vec<(u8, u128, bool)> {
+        deposits
+            .iter()
+            .map(|d| (d.receiver.0[19], d.amount, d.reduced))
+            .collect()
+    }
+
+    #[test]
+    fn cap() {
+        let mut capped = deposits(&[10 * GRT, GRT, 300 * GRT]);
+        cap_deposits(&mut capped, 400 * GRT);
+        assert_eq!(
+            amounts(&capped),
+            [(0, 10 * GRT, false), (1, GRT, false), (2, 300 * GRT, false)]
+        );
+
+        let mut capped = deposits(&[10 * GRT, GRT, 300 * GRT]);
+        cap_deposits(&mut capped, 200 * GRT);
+        assert_eq!(
+            amounts(&capped),
+            [(0, 10 * GRT, false), (1, GRT, false), (2, 189 * GRT, true)]
+        );
+    }
+
+    #[test]
+    fn reduce_max_adjustment() {
+        let reduced = reduce_adjustments(vec![(0, 20_000 * GRT), (1, 500 * GRT)]);
+        assert_eq!(reduced, [(0, 9_502 * GRT), (1, 500 * GRT)]);
+
+        // Every receiver keeps at least a minimum deposit, even if the total exceeds
+        // `MAX_ADJUSTMENT`.
+        let reduced = reduce_adjustments((0..6_000).map(|i| (i, 100 * GRT)).collect());
+        assert_eq!(reduced.len(), 6_000);
+        assert!(reduced.iter().all(|(_, amount)| *amount >= MIN_DEPOSIT));
+        assert!(reduced.iter().map(|(_, amount)| amount).sum::<u128>() > MAX_ADJUSTMENT);
+    }
+
+    #[test]
+    fn cap_below_minimum_deposits() {
+        // The cap holds even if it can't cover a minimum deposit for each receiver.
+        let mut capped = deposits(&[100 * GRT; 20]);
+        cap_deposits(&mut capped, 10 * GRT);
+        assert_eq!(capped.iter().map(|d| d.amount).sum::<u128>(), 10 * GRT);
+        assert_eq!(
+            amounts(&capped),
+            (0..5).map(|i| (i, MIN_DEPOSIT, true)).collect::<Vec<_>>()
+        );
+
+        let mut capped = deposits(&[GRT, GRT / 2, 100 * GRT]);
+        cap_deposits(&mut capped, 3 * GRT);
+        assert_eq!(
+            amounts(&capped),
+            [(0, GRT, false), (1, GRT / 2, false), (2, 3 * GRT / 2, true)]
+        );
+
+        let mut capped = deposits(&[100 * GRT; 3]);
+        cap_deposits(&mut capped, 0);
+        assert!(capped.is_empty());
+    }
+
+    #[test]
+    fn outstanding() {
+        let mut fees = Fees {
+            receipts: 10 * GRT,
+            ..Default::default()
+        };
+        assert_eq!(fees.outstanding(), 10 * GRT);
+        // Fees collected in the past don't reduce the receipts of the window.
+        fees.add_rav(100 * GRT, 100 * GRT);
+        assert_eq!(fees.outstanding(), 10 * GRT);
+        fees.add_rav(30 * GRT, 5 * GRT);
+        assert_eq!(fees.outstanding(), 25 * GRT);
+        // Collected tokens are only subtracted from their own allocation.
+        fees.add_rav(GRT, 2 * GRT);
+        assert_eq!(fees.outstanding(), 25 * GRT);
+        fees.receipts = 40 * GRT;
+        assert_eq!(fees.outstanding(), 40 * GRT);
+        assert_eq!((fees.ravs, fees.collected), (131 * GRT, 107 * GRT));
+    }
 
     #[test]
     fn next_balance() {
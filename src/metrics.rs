@@ -1,7 +1,8 @@
 use lazy_static::lazy_static;
 use prometheus::{
-    register_gauge, register_gauge_vec, register_histogram, register_int_counter,
-    register_int_gauge, Gauge, GaugeVec, Histogram, IntCounter, IntGauge,
+    register_counter_vec, register_gauge_vec, register_histogram, register_int_counter,
+    register_int_counter_vec, register_int_gauge_vec, CounterVec, GaugeVec, Histogram, IntCounter,
+    IntCounterVec, IntGaugeVec,
 };
 
 lazy_static! {
@@ -9,39 +10,77 @@ lazy_static! {
 }
 
 pub struct Metrics {
-    pub total_debt_grt: Gauge,
-    pub total_balance_grt: Gauge,
-    pub total_adjustment_grt: Gauge,
-    pub receiver_count: IntGauge,
+    pub total_debt_grt: GaugeVec,
+    pub total_balance_grt: GaugeVec,
+    pub total_adjustment_grt: GaugeVec,
+    pub receiver_count: IntGaugeVec,
+    pub paused: IntGaugeVec,
+    pub stale_data: IntGaugeVec,
     pub loop_duration: Histogram,
     pub deposit: ResponseMetrics,
+    pub deposit_mismatch: IntCounterVec,
+    pub deposit_gas_used: IntGaugeVec,
+    pub deposit_gas_price_gwei: GaugeVec,
     // Per-receiver metrics
     pub debt_grt: GaugeVec,
     pub balance_grt: GaugeVec,
     pub adjustment_grt: GaugeVec,
+    pub collected_grt: GaugeVec,
+    pub signer_receipts_grt: GaugeVec,
+    pub signer_recent_receipts_grt: GaugeVec,
+    pub allocation_fee_mismatch: IntGaugeVec,
+    // Chain log follower metrics
+    pub log_block: IntGaugeVec,
+    pub ravs_collected: IntCounterVec,
+    pub rav_collected_grt: CounterVec,
+    pub thawing_grt: GaugeVec,
+    // Kafka metrics
+    pub rejected_messages: IntCounterVec,
+    pub kafka_lag: IntGaugeVec,
+    pub kafka_message_timestamp: IntGaugeVec,
+    pub kafka_consumer_healthy: IntGaugeVec,
+    pub kafka_consumer_restarts: IntCounterVec,
+    pub kafka_consumer_ready: IntGaugeVec,
+    pub kafka_bootstrap_remaining: IntGaugeVec,
 }
 
 impl Metrics {
     fn new() -> Self {
         Self {
-            total_debt_grt: register_gauge!(
+            total_debt_grt: register_gauge_vec!(
                 "escrow_total_debt_grt",
-                "total outstanding debt across all receivers in GRT"
+                "total outstanding debt across all receivers in GRT",
+                &["payer", "chain_id"]
             )
             .unwrap(),
-            total_balance_grt: register_gauge!(
+            total_balance_grt: register_gauge_vec!(
                 "escrow_total_balance_grt",
-                "total escrow balance across all receivers in GRT"
+                "total escrow balance across all receivers in GRT",
+                &["payer", "chain_id"]
             )
             .unwrap(),
-            total_adjustment_grt: register_gauge!(
+            total_adjustment_grt: register_gauge_vec!(
                 "escrow_total_adjustment_grt",
-                "total GRT deposited in the last cycle"
+                "total GRT deposited in the last cycle",
+                &["payer", "chain_id"]
             )
             .unwrap(),
-            receiver_count: register_int_gauge!(
+            receiver_count: register_int_gauge_vec!(
                 "escrow_receiver_count",
-                "number of receivers being tracked"
+                "number of escrow accounts being tracked",
+                &["payer", "chain_id"]
+            )
+            .unwrap(),
+            paused: register_int_gauge_vec!(
+                "escrow_paused",
+                "1 if deposits are rejected because PaymentsEscrow is paused",
+                &["payer", "chain_id"]
+            )
+            .unwrap(),
+            stale_data: register_int_gauge_vec!(
+                "escrow_stale_data",
+                "1 if deposits are skipped or capped because the Kafka data is stale",
+                &["payer", "chain_id"]
             )
             .unwrap(),
             loop_duration: register_histogram!(
@@ -50,22 +89,130 @@ impl Metrics {
             )
             .unwrap(),
             deposit: ResponseMetrics::new("escrow_deposit", "escrow deposit transaction"),
+            deposit_mismatch: register_int_counter_vec!(
+                "escrow_deposit_mismatch",
+                "receivers for which deposit events differ from the planned amount",
+                &["payer", "chain_id"]
+            )
+            .unwrap(),
+            deposit_gas_used: register_int_gauge_vec!(
+                "escrow_deposit_gas_used",
+                "gas used by the last deposit transaction",
+                &["payer", "chain_id"]
+            )
+            .unwrap(),
+            deposit_gas_price_gwei: register_gauge_vec!(
+                "escrow_deposit_gas_price_gwei",
+                "effective gas price of the last deposit transaction in gwei",
+                &["payer", "chain_id"]
+            )
+            .unwrap(),
             debt_grt: register_gauge_vec!(
                 "escrow_debt_grt",
-                "outstanding debt per receiver in GRT",
-                &["receiver"]
+                "outstanding debt per escrow account in GRT",
+                &["receiver", "collector", "payer", "chain_id"]
             )
             .unwrap(),
             balance_grt: register_gauge_vec!(
                 "escrow_balance_grt",
-                "escrow balance per receiver in GRT",
-                &["receiver"]
+                "escrow balance per escrow account in GRT",
+                &["receiver", "collector", "payer", "chain_id"]
             )
             .unwrap(),
             adjustment_grt: register_gauge_vec!(
                 "escrow_adjustment_grt",
-                "last adjustment per receiver in GRT",
-                &["receiver"]
+                "last adjustment per escrow account in GRT",
+                &["receiver", "collector", "payer", "chain_id"]
+            )
+            .unwrap(),
+            collected_grt: register_gauge_vec!(
+                "escrow_collected_grt",
+                "tokens collected for the RAVs of active allocations per receiver in GRT",
+                &["receiver", "payer", "chain_id"]
+            )
+            .unwrap(),
+            signer_receipts_grt: register_gauge_vec!(
+                "escrow_signer_receipts_grt",
+                "receipts within the window per signer in GRT",
+                &["signer", "payer", "chain_id"]
+            )
+            .unwrap(),
+            signer_recent_receipts_grt: register_gauge_vec!(
+                "escrow_signer_recent_receipts_grt",
+                "receipts within the short-term window per signer in GRT",
+                &["signer", "payer", "chain_id"]
+            )
+            .unwrap(),
+            allocation_fee_mismatch: register_int_gauge_vec!(
+                "escrow_allocation_fee_mismatch",
+                "active allocations whose latest RAV exceeds their receipts in the breakdown",
+                &["payer", "chain_id"]
+            )
+            .unwrap(),
+            log_block: register_int_gauge_vec!(
+                "escrow_log_block",
+                "last block processed by the chain log follower",
+                &["payer", "chain_id"]
+            )
+            .unwrap(),
+            ravs_collected: register_int_counter_vec!(
+                "escrow_ravs_collected",
+                "RAVCollected events per receiver",
+                &["receiver", "payer", "chain_id"]
+            )
+            .unwrap(),
+            rav_collected_grt: register_counter_vec!(
+                "escrow_rav_collected_grt",
+                "tokens collected through RAVs per receiver in GRT",
+                &["receiver", "payer", "chain_id"]
+            )
+            .unwrap(),
+            thawing_grt: register_gauge_vec!(
+                "escrow_thawing_grt",
+                "tokens thawing per escrow account in GRT",
+                &["receiver", "collector", "payer", "chain_id"]
+            )
+            .unwrap(),
+            rejected_messages: register_int_counter_vec!(
+                "escrow_rejected_messages",
+                "Kafka messages rejected due to an invalid payload or fee",
+                &["topic"]
+            )
+            .unwrap(),
+            kafka_lag: register_int_gauge_vec!(
+                "escrow_kafka_lag",
+                "messages not yet consumed per Kafka partition",
+                &["topic", "partition"]
+            )
+            .unwrap(),
+            kafka_message_timestamp: register_int_gauge_vec!(
+                "escrow_kafka_message_timestamp_seconds",
+                "timestamp of the latest processed Kafka message",
+                &["topic"]
+            )
+            .unwrap(),
+            kafka_consumer_healthy: register_int_gauge_vec!(
+                "escrow_kafka_consumer_healthy",
+                "0 while the Kafka consumer task is being restarted",
+                &["topic"]
+            )
+            .unwrap(),
+            kafka_consumer_restarts: register_int_counter_vec!(
+                "escrow_kafka_consumer_restarts",
+                "restarts of the Kafka consumer task",
+                &["topic"]
+            )
+            .unwrap(),
+            kafka_consumer_ready: register_int_gauge_vec!(
+                "escrow_kafka_consumer_ready",
+                "1 once the Kafka consumer has caught up with the end of each partition",
+                &["topic"]
+            )
+            .unwrap(),
+            kafka_bootstrap_remaining: register_int_gauge_vec!(
+                "escrow_kafka_bootstrap_remaining",
+                "aggregated topic messages left to replay on startup per partition",
+                &["topic", "partition"]
             )
             .unwrap(),
         }
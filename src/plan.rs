@@ -0,0 +1,162 @@
+use std::path::Path;
+
+use alloy::primitives::Address;
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+/// Version of the plan file format. This must be incremented on incompatible changes.
+pub const VERSION: u32 = 2;
+
+/// Escrow changes computed for a single cycle. These are written to a file by the `plan` command,
+/// to be reviewed and later executed by the `apply` command.
+#[derive(Serialize, Deserialize)]
+pub struct Plan {
+    pub version: u32,
+    /// Creation time, in unix seconds
+    pub created_at: u64,
+    pub chain_id: u64,
+    /// Block at which the on-chain balances and allowance were checked
+    pub block_number: u64,
+    pub payer: Address,
+    /// Account funding the deposits, if it differs from the payer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<Address>,
+    pub payments_escrow: Address,
+    /// GraphTallyCollector, for which the receipts and RAVs are tracked
+    pub collector: Address,
+    /// Signers used to filter the receipts and RAVs
+    pub signers: Vec<Address>,
+    pub approval: Option<Approval>,
+    pub deposits: Vec<Deposit>,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+pub struct Approval {
+    /// Allowance at the time of planning
+    #[serde_as(as = "DisplayFromStr")]
+    pub allowance: u128,
+    /// Allowance to approve
+    #[serde_as(as = "DisplayFromStr")]
+    pub amount: u128,
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Deposit {
+    pub collector: Address,
+    pub receiver: Address,
+    /// Escrow balance at the time of planning
+    #[serde_as(as = "DisplayFromStr")]
+    pub balance: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub receipts: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub ravs: u128,
+    /// Tokens already collected from escrow for the RAVs
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub collected: u128,
+    /// Minimum debt set in the config
+    #[serde_as(as = "DisplayFromStr")]
+    pub minimum_debt: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub debt: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub target_balance: u128,
+    #[serde_as(as = "DisplayFromStr")]
+    pub amount: u128,
+    pub reason: Reason,
+    /// The amount was reduced to stay within the maximum adjustment per cycle.
+    pub reduced: bool,
+}
+
+/// Input that determined the debt of a receiver
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    MinimumBalance,
+    Receipts,
+    Ravs,
+    ConfiguredDebt,
+}
+
+impl Reason {
+    pub fn new(receipts: u128, ravs: u128, minimum_debt: u128) -> Self {
+        let debt = receipts.max(ravs).max(minimum_debt);
+        if debt == 0 {
+            Self::MinimumBalance
+        } else if debt == receipts {
+            Self::Receipts
+        } else if debt == ravs {
+            Self::Ravs
+        } else {
+            Self::ConfiguredDebt
+        }
+    }
+}
+
+impl Plan {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let plan: Self = std::fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| serde_json::from_str(&s).map_err(anyhow::Error::from))
+            .with_context(|| format!("failed to load plan from {}", path.display()))?;
+        anyhow::ensure!(
+            plan.version == VERSION,
+            "unsupported plan version {}, expected {VERSION}",
+            plan.version,
+        );
+        Ok(plan)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        crate::journal::write_json_atomic(path, self)
+            .with_context(|| format!("failed to write plan to {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::Address;
+
+    use super::{Plan, Reason, VERSION};
+
+    #[test]
+    fn load_rejects_other_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("plan.json");
+        let mut plan = Plan {
+            version: VERSION,
+            created_at: 0,
+            chain_id: 421614,
+            block_number: 1,
+            payer: Address::repeat_byte(1),
+            sender: None,
+            payments_escrow: Address::repeat_byte(2),
+            collector: Address::repeat_byte(3),
+            signers: vec![Address::repeat_byte(4)],
+            approval: None,
+            deposits: vec![],
+        };
+        plan.save(&path).unwrap();
+        assert_eq!(Plan::load(&path).unwrap().payer, plan.payer);
+
+        plan.version = VERSION + 1;
+        plan.save(&path).unwrap();
+        let load_err = Plan::load(&path).err().unwrap();
+        assert!(format!("{load_err:#}").contains("unsupported plan version"));
+    }
+
+    #[test]
+    fn reason() {
+        assert_eq!(Reason::new(0, 0, 0), Reason::MinimumBalance);
+        assert_eq!(Reason::new(3, 2, 1), Reason::Receipts);
+        assert_eq!(Reason::new(2, 3, 1), Reason::Ravs);
+        assert_eq!(Reason::new(1, 2, 3), Reason::ConfiguredDebt);
+        // Ties go to the receipts, then to the RAVs.
+        assert_eq!(Reason::new(3, 3, 3), Reason::Receipts);
+        assert_eq!(Reason::new(0, 3, 3), Reason::Ravs);
+    }
+}
@@ -0,0 +1,462 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use alloy::{
+    primitives::{Address, Bytes, B256, U256},
+    providers::{DynProvider, Provider as _},
+    signers::{local::PrivateKeySigner, SignerSync as _},
+    sol,
+    sol_types::{eip712_domain, SolStruct as _},
+};
+use anyhow::{anyhow, Context as _};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+sol!(
+    #[allow(missing_docs)]
+    #[sol(rpc)]
+    Safe,
+    r#"[{"type":"function","name":"nonce","inputs":[],"outputs":[{"name":"","type":"uint256"}],"stateMutability":"view"}]"#
+);
+use Safe::SafeInstance;
+sol! {
+    struct SafeTx {
+        address to;
+        uint256 value;
+        bytes data;
+        uint8 operation;
+        uint256 safeTxGas;
+        uint256 baseGas;
+        uint256 gasPrice;
+        address gasToken;
+        address refundReceiver;
+        uint256 nonce;
+    }
+}
+
+/// Proposes transactions to a Safe multisig, so that they are executed once enough owners have
+/// signed them. Proposals are posted to a Safe transaction service and/or written to a directory
+/// as Safe Transaction Builder batches.
+pub struct Proposer {
+    safe: SafeInstance<DynProvider>,
+    /// Owner or delegate signing the proposals
+    signer: Option<PrivateKeySigner>,
+    transaction_service: Option<TransactionService>,
+    output: Option<PathBuf>,
+    /// Safe nonce of the last proposal. Proposals made before it is executed are queued after it.
+    /// `None` until restored from the proposals queued before a restart.
+    pending_nonce: Mutex<Option<Option<u64>>>,
+}
+
+impl Proposer {
+    pub fn new(
+        safe: Address,
+        provider: DynProvider,
+        signer: Option<PrivateKeySigner>,
+        transaction_service: Option<Url>,
+        output: Option<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            transaction_service.is_some() || output.is_some(),
+            "safe requires a transaction_service or output",
+        );
+        anyhow::ensure!(
+            transaction_service.is_none() || signer.is_some(),
+            "safe transaction_service requires a secret_key to sign proposals",
+        );
+        let transaction_service = transaction_service
+            .map(TransactionService::new)
+            .transpose()?;
+        Ok(Self {
+            safe: SafeInstance::new(safe, provider),
+            signer,
+            transaction_service,
+            output,
+            pending_nonce: Mutex::new(None),
+        })
+    }
+
+    async fn nonce(&self) -> anyhow::Result<u64> {
+        self.safe
+            .nonce()
+            .call()
+            .await
+            .context("get safe nonce")?
+            .try_into()
+            .context("result out of bounds")
+    }
+
+    /// Safe nonce of the last proposal. On first use, it is restored from the proposals queued in
+    /// the transaction service or written to the output directory, so that proposals are not made
+    /// again after a restart.
+    async fn pending_nonce(&self) -> anyhow::Result<Option<u64>> {
+        if let Some(pending_nonce) = *self.pending_nonce.lock().unwrap() {
+            return Ok(pending_nonce);
+        }
+        let nonce = self.nonce().await?;
+        let mut queued = None;
+        if let Some(transaction_service) = &self.transaction_service {
+            queued = transaction_service
+                .queued_nonce(*self.safe.address(), nonce)
+                .await?;
+        }
+        if let Some(output) = &self.output {
+            queued = queued.max(output_nonce(output)?.filter(|n| *n >= nonce));
+        }
+        if let Some(queued) = queued {
+            tracing::info!(
+                safe_nonce = nonce,
+                queued,
+                "safe proposals awaiting execution"
+            );
+        }
+        Ok(*self.pending_nonce.lock().unwrap().get_or_insert(queued))
+    }
+
+    /// Returns true while the last proposal hasn't been executed or replaced.
+    pub async fn pending(&self) -> anyhow::Result<bool> {
+        match self.pending_nonce().await? {
+            Some(pending_nonce) => Ok(self.nonce().await? <= pending_nonce),
+            None => Ok(false),
+        }
+    }
+
+    pub async fn propose(&self, label: &str, to: Address, data: Bytes) -> anyhow::Result<()> {
+        let chain_id = self.safe.provider().get_chain_id().await?;
+        let pending_nonce = self.pending_nonce().await?;
+        let nonce = match pending_nonce {
+            Some(pending_nonce) => self.nonce().await?.max(pending_nonce + 1),
+            None => self.nonce().await?,
+        };
+        let proposal = Proposal::new(
+            *self.safe.address(),
+            chain_id,
+            nonce,
+            to,
+            data,
+            self.signer.as_ref(),
+        )?;
+        if let Some(output) = &self.output {
+            let path = output.join(format!("{}-{nonce}-{label}.json", proposal.created_at));
+            crate::journal::write_json_atomic(&path, &proposal.batch(label))
+                .with_context(|| format!("failed to write {}", path.display()))?;
+            tracing::info!(path = %path.display(), "safe transaction batch written");
+        }
+        if let Some(transaction_service) = &self.transaction_service {
+            transaction_service.propose(&proposal).await?;
+            tracing::info!(safe_tx_hash = %proposal.contract_transaction_hash, "safe transaction proposed");
+        }
+        *self.pending_nonce.lock().unwrap() = Some(Some(nonce));
+        Ok(())
+    }
+}
+
+/// Highest nonce of the batches in the output directory, named `<created_at>-<nonce>-<label>.json`
+fn output_nonce(output: &Path) -> anyhow::Result<Option<u64>> {
+    let entries = std::fs::read_dir(output)
+        .with_context(|| format!("failed to read {}", output.display()))?;
+    let mut nonce = None;
+    for entry in entries {
+        let name = entry?.file_name();
+        let batch_nonce = name
+            .to_str()
+            .and_then(|name| name.strip_suffix(".json"))
+            .and_then(|name| name.split('-').nth(1))
+            .and_then(|n| n.parse::<u64>().ok());
+        nonce = nonce.max(batch_nonce);
+    }
+    Ok(nonce)
+}
+
+/// A Safe transaction, in the format expected by the Safe transaction service.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Proposal {
+    #[serde(skip)]
+    safe: Address,
+    #[serde(skip)]
+    chain_id: u64,
+    #[serde(skip)]
+    created_at: u64,
+    to: Address,
+    value: String,
+    data: Bytes,
+    operation: u8,
+    safe_tx_gas: String,
+    base_gas: String,
+    gas_price: String,
+    gas_token: Address,
+    refund_receiver: Address,
+    nonce: u64,
+    contract_transaction_hash: B256,
+    sender: Option<Address>,
+    signature: Option<Bytes>,
+    origin: String,
+}
+
+impl Proposal {
+    pub fn new(
+        safe: Address,
+        chain_id: u64,
+        nonce: u64,
+        to: Address,
+        data: Bytes,
+        signer: Option<&PrivateKeySigner>,
+    ) -> anyhow::Result<Self> {
+        let tx = SafeTx {
+            to,
+            value: U256::ZERO,
+            data: data.clone(),
+            operation: 0,
+            safeTxGas: U256::ZERO,
+            baseGas: U256::ZERO,
+            gasPrice: U256::ZERO,
+            gasToken: Address::ZERO,
+            refundReceiver: Address::ZERO,
+            nonce: U256::from(nonce),
+        };
+        let domain = eip712_domain! {
+            chain_id: chain_id,
+            verifying_contract: safe,
+        };
+        let hash = tx.eip712_signing_hash(&domain);
+        let signature = signer
+            .map(|signer| signer.sign_hash_sync(&hash))
+            .transpose()
+            .context("sign safe transaction")?;
+        Ok(Self {
+            safe,
+            chain_id,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            to,
+            value: "0".into(),
+            data,
+            operation: 0,
+            safe_tx_gas: "0".into(),
+            base_gas: "0".into(),
+            gas_price: "0".into(),
+            gas_token: Address::ZERO,
+            refund_receiver: Address::ZERO,
+            nonce,
+            contract_transaction_hash: hash,
+            sender: signer.map(|s| s.address()),
+            signature: signature.map(|s| s.as_bytes().into()),
+            origin: "tap-escrow-manager".into(),
+        })
+    }
+
+    /// The proposal as a Safe Transaction Builder batch, which can be imported in the Safe UI.
+    fn batch(&self, label: &str) -> serde_json::Value {
+        serde_json::json!({
+            "version": "1.0",
+            "chainId": self.chain_id.to_string(),
+            "createdAt": self.created_at * 1000,
+            "meta": {
+                "name": format!("tap-escrow-manager {label}"),
+                "description": format!("safeTxHash {}", self.contract_transaction_hash),
+                "createdFromSafeAddress": self.safe,
+            },
+            "transactions": [{
+                "to": self.to,
+                "value": self.value,
+                "data": self.data,
+                "contractMethod": null,
+                "contractInputsValues": null,
+            }],
+        })
+    }
+}
+
+struct TransactionService {
+    http: reqwest::Client,
+    url: Url,
+}
+
+impl TransactionService {
+    fn new(url: Url) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .context("failed to create transaction service client")?;
+        Ok(Self { http, url })
+    }
+
+    /// Highest nonce of the transactions of the Safe that are queued in the service and not
+    /// executed yet, from `nonce` on.
+    async fn queued_nonce(&self, safe: Address, nonce: u64) -> anyhow::Result<Option<u64>> {
+        #[derive(Deserialize)]
+        struct Page {
+            results: Vec<Transaction>,
+        }
+        #[derive(Deserialize)]
+        struct Transaction {
+            nonce: u64,
+        }
+        let mut url = self
+            .url
+            .join(&format!("api/v1/safes/{safe}/multisig-transactions/"))
+            .context("invalid transaction service URL")?;
+        url.query_pairs_mut()
+            .append_pair("executed", "false")
+            .append_pair("nonce__gte", &nonce.to_string())
+            .append_pair("ordering", "-nonce")
+            .append_pair("limit", "1");
+        let response = self
+            .http
+            .get(url)
+            .send()
+            .await
+            .context("get queued safe transactions")?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("safe transaction service error {status}: {body}"));
+        }
+        let body = response
+            .text()
+            .await
+            .context("get queued safe transactions")?;
+        let page: Page = serde_json::from_str(&body).context("invalid queued safe transactions")?;
+        Ok(page.results.first().map(|tx| tx.nonce))
+    }
+
+    async fn propose(&self, proposal: &Proposal) -> anyhow::Result<()> {
+        let url = self
+            .url
+            .join(&format!(
+                "api/v1/safes/{}/multisig-transactions/",
+                proposal.safe
+            ))
+            .context("invalid transaction service URL")?;
+        let response = self
+            .http
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(proposal)?)
+            .send()
+            .await
+            .context("propose safe transaction")?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("safe transaction service error {status}: {body}"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use alloy::{
+        primitives::{address, Address, Bytes, Signature},
+        signers::local::PrivateKeySigner,
+    };
+    use axum::{
+        extract::{Path, RawQuery},
+        routing, Router,
+    };
+    use reqwest::Url;
+    use tokio::{net::TcpListener, sync::Mutex};
+
+    use super::{output_nonce, Proposal, TransactionService};
+
+    #[tokio::test]
+    async fn propose_to_transaction_service() {
+        let received: Arc<Mutex<Option<(String, String)>>> = Default::default();
+        let router = Router::new().route(
+            "/api/v1/safes/{safe}/multisig-transactions/",
+            routing::post({
+                let received = received.clone();
+                move |Path(safe): Path<String>, body: String| async move {
+                    *received.lock().await = Some((safe, body));
+                    axum::http::StatusCode::CREATED
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: Url = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        let safe = address!("0x1111111111111111111111111111111111111111");
+        let to = address!("0x2222222222222222222222222222222222222222");
+        let signer = PrivateKeySigner::random();
+        let proposal = Proposal::new(
+            safe,
+            42161,
+            7,
+            to,
+            Bytes::from_static(&[1, 2]),
+            Some(&signer),
+        )
+        .unwrap();
+        let service = TransactionService::new(url).unwrap();
+        service.propose(&proposal).await.unwrap();
+
+        let (path_safe, body) = received.lock().await.take().unwrap();
+        assert_eq!(path_safe, safe.to_string());
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let field = |name: &str| serde_json::from_value::<Address>(body[name].clone()).unwrap();
+        assert_eq!(field("to"), to);
+        assert_eq!(field("sender"), signer.address());
+        assert_eq!(body["data"], "0x0102");
+        assert_eq!(body["nonce"], 7);
+        let signature: Bytes = serde_json::from_value(body["signature"].clone()).unwrap();
+        let signature = Signature::try_from(signature.as_ref()).unwrap();
+        let recovered = signature
+            .recover_address_from_prehash(&proposal.contract_transaction_hash)
+            .unwrap();
+        assert_eq!(recovered, signer.address());
+    }
+
+    #[tokio::test]
+    async fn queued_nonce_from_transaction_service() {
+        let router = Router::new().route(
+            "/api/v1/safes/{safe}/multisig-transactions/",
+            routing::get(|RawQuery(query): RawQuery| async move {
+                let query = query.unwrap_or_default();
+                assert!(query.contains("executed=false"));
+                assert!(query.contains("ordering=-nonce"));
+                if query.contains("nonce__gte=7") {
+                    r#"{"count":2,"results":[{"nonce":9},{"nonce":8}]}"#
+                } else {
+                    r#"{"count":0,"results":[]}"#
+                }
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: Url = format!("http://{}/", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        let safe = address!("0x1111111111111111111111111111111111111111");
+        let service = TransactionService::new(url).unwrap();
+        assert_eq!(service.queued_nonce(safe, 7).await.unwrap(), Some(9));
+        assert_eq!(service.queued_nonce(safe, 10).await.unwrap(), None);
+    }
+
+    #[test]
+    fn queued_nonce_from_output() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(output_nonce(dir.path()).unwrap(), None);
+        for name in [
+            "1700000000-3-deposit.json",
+            "1700000100-12-deposit.json",
+            "1700000200-4-thaw.json",
+            "notes.txt",
+        ] {
+            std::fs::write(dir.path().join(name), "{}").unwrap();
+        }
+        assert_eq!(output_nonce(dir.path()).unwrap(), Some(12));
+    }
+}
@@ -36,10 +36,11 @@ pub async fn authorized_signers(
     Ok(signers)
 }
 
+/// Escrow balances of the payer, keyed by (collector, receiver)
 pub async fn escrow_accounts(
     network_subgraph: &mut SubgraphClient,
     payer: &Address,
-) -> anyhow::Result<HashMap<Address, u128>> {
+) -> anyhow::Result<HashMap<(Address, Address), u128>> {
     let query = format!(
         r#"
         paymentsEscrowAccounts(
@@ -54,6 +55,9 @@ pub async fn escrow_accounts(
         ) {{
             id
             balance
+            collector {{
+                id
+            }}
             receiver {{
                 id
             }}
@@ -65,10 +69,11 @@ pub async fn escrow_accounts(
     struct EscrowAccount {
         #[serde_as(as = "serde_with::DisplayFromStr")]
         balance: u128,
-        receiver: Receiver,
+        collector: Id,
+        receiver: Id,
     }
     #[derive(serde::Deserialize)]
-    struct Receiver {
+    struct Id {
         id: Address,
     }
     let response = network_subgraph
@@ -77,7 +82,7 @@ pub async fn escrow_accounts(
     match response {
         Ok(accounts) => Ok(accounts
             .into_iter()
-            .map(|a| (a.receiver.id, a.balance))
+            .map(|a| ((a.collector.id, a.receiver.id), a.balance))
             .collect()),
         Err(PaginatedQueryError::EmptyResponse) => Ok(Default::default()),
         Err(err) => Err(anyhow!(err)),